@@ -0,0 +1,380 @@
+use flate2::read::{DeflateDecoder, GzDecoder};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, Read, Seek, SeekFrom, Take};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+use storage::{normalize_path, Metadata, Storage, StorageWriter, WritePolicy};
+use tar;
+use zip;
+use zip::CompressionMethod;
+use zip::result::ZipError;
+
+/// The archive formats files can be served from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ArchiveFormat {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+/// The file name extensions of the archive formats, in the order they are looked up.
+const EXTENSIONS: [(&str, ArchiveFormat); 4] = [(".tar", ArchiveFormat::Tar),
+                                                (".tar.gz", ArchiveFormat::TarGz),
+                                                (".tgz", ArchiveFormat::TarGz),
+                                                (".zip", ArchiveFormat::Zip)];
+
+/// Where the contents of a file are stored inside its archive.
+#[derive(Clone, Copy, Debug)]
+struct Member {
+    /// The offset of the contents in the archive,
+    /// or in the decompressed archive for a `.tar.gz`.
+    offset: u64,
+    /// The size of the file in bytes.
+    len: u64,
+    /// The size of the deflated contents of a zip member,
+    /// or `None` if the contents are stored as they are.
+    deflated_len: Option<u64>,
+}
+
+/// The largest total size of the `.tar.gz` members cached by default, 256 MiB.
+const DEFAULT_CACHE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The files of an archive by their path inside the archive.
+struct ArchiveIndex {
+    /// When the archive was last modified when it was indexed.
+    modified: Option<SystemTime>,
+    members: HashMap<String, Member>,
+}
+
+/// A member of an archive that a requested path refers to.
+struct Found {
+    archive: PathBuf,
+    format: ArchiveFormat,
+    /// The path of the member inside the archive.
+    name: String,
+    member: Member,
+}
+
+/// Identifies the contents of a cached member by the archive
+/// and the path of the member.
+type CacheKey = (PathBuf, String);
+
+/// The decompressed contents of recently requested `.tar.gz` members,
+/// least recently used first.
+struct MemberCache {
+    entries: VecDeque<(CacheKey, Arc<Vec<u8>>)>,
+    /// The total size of the cached contents in bytes.
+    size: u64,
+    /// The largest total size of the cached contents in bytes.
+    capacity: u64,
+}
+
+impl MemberCache {
+    /// Returns the contents of a member if they are cached,
+    /// marking them as the most recently used.
+    fn get(&mut self, key: &CacheKey) -> Option<Arc<Vec<u8>>> {
+        let pos = self.entries.iter().position(|(cached, _)| cached == key)?;
+        let entry = self.entries.remove(pos)?;
+        let contents = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(contents)
+    }
+
+    /// Caches the contents of a member, dropping the least recently used
+    /// members to make room.
+    fn insert(&mut self, key: CacheKey, contents: Arc<Vec<u8>>) {
+        self.entries.retain(|(cached, _)| *cached != key);
+        self.entries.push_back((key, contents));
+        self.size = self.entries.iter().map(|(_, contents)| contents.len() as u64).sum();
+        while self.size > self.capacity {
+            match self.entries.pop_front() {
+                Some((_, contents)) => self.size -= contents.len() as u64,
+                None => break,
+            }
+        }
+    }
+}
+
+/// The contents of a cached member, shared with the cache while they are sent.
+struct SharedContents(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedContents {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Decompresses a member of a `.tar.gz` while it is read, and caches the
+/// contents once the whole member has been read if it fits into the cache.
+struct CompressedMember {
+    reader: Take<GzDecoder<File>>,
+    /// The contents read so far, or `None` if the member is not cached.
+    contents: Option<Vec<u8>>,
+    /// The number of bytes of the member that have not been read yet.
+    remaining: u64,
+    key: CacheKey,
+    cache: Arc<Mutex<MemberCache>>,
+}
+
+impl Read for CompressedMember {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let amount = self.reader.read(buf)?;
+        if amount == 0 && self.remaining > 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "archive is truncated"));
+        }
+        self.remaining -= amount as u64;
+        if let Some(ref mut contents) = self.contents {
+            contents.extend_from_slice(&buf[..amount]);
+        }
+        if self.remaining == 0 {
+            if let Some(contents) = self.contents.take() {
+                lock_cache(&self.cache).insert(self.key.clone(), Arc::new(contents));
+            }
+        }
+        Ok(amount)
+    }
+}
+
+/// Serves the files inside a directory of `.tar`, `.tar.gz`, `.tgz` and
+/// `.zip` archives without unpacking them.
+///
+/// Every archive appears as a directory named after the archive without its
+/// extension, so `boot/vmlinuz` is the member `vmlinuz` of `boot.tar`,
+/// `boot.tar.gz`, `boot.tgz` or `boot.zip`, looked up in that order.
+/// Requested files are streamed out of the archive. The storage is read-only
+/// and refuses every upload.
+///
+/// The archives are indexed only when the storage is created, so that
+/// requests never wait for an archive to be read. Archives added later are
+/// not served, and the members of an archive that changed since are refused.
+/// A `.tar.gz` cannot be seeked, so it is decompressed up to the start of
+/// a member when the member is requested, and the member is decompressed
+/// while it is sent. The members are kept in memory up to the cache size so
+/// that later requests are served without decompressing; a member larger
+/// than the cache is decompressed for every request.
+pub struct ArchiveStorage {
+    /// The canonical path of the directory containing the archives.
+    dir: PathBuf,
+    /// The members of the archives by the path of the archive.
+    indexes: HashMap<PathBuf, ArchiveIndex>,
+    /// The decompressed `.tar.gz` members that were requested recently.
+    cache: Arc<Mutex<MemberCache>>,
+}
+
+impl ArchiveStorage {
+    /// Creates a storage for the archives in the given directory, which has
+    /// to exist, and indexes them. Archives that cannot be read are skipped.
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<ArchiveStorage> {
+        let dir = fs::canonicalize(dir)?;
+        let mut indexes = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let archive = entry?.path();
+            let format = match archive_format(&archive) {
+                Some(format) if archive.is_file() => format,
+                _ => continue,
+            };
+            info!("Indexing archive {:?}", archive);
+            match index_archive(&archive, format) {
+                Ok(index) => {
+                    indexes.insert(archive, index);
+                }
+                Err(err) => warn!("Skipping archive {:?}: {}", archive, err),
+            }
+        }
+        Ok(ArchiveStorage {
+            dir,
+            indexes,
+            cache: Arc::new(Mutex::new(MemberCache {
+                entries: VecDeque::new(),
+                size: 0,
+                capacity: DEFAULT_CACHE_SIZE,
+            })),
+        })
+    }
+
+    /// Sets the largest total size in bytes of the decompressed `.tar.gz`
+    /// members kept in memory, which is 256 MiB by default.
+    pub fn cache_size(self, size: u64) -> ArchiveStorage {
+        self.cache().capacity = size;
+        self
+    }
+
+    /// Finds the archive and the member that a path refers to.
+    fn find(&self, path: &str) -> io::Result<Found> {
+        let (name, member) = match path.find('/') {
+            Some(pos) => (&path[..pos], &path[pos + 1..]),
+            None => return Err(not_found()),
+        };
+        let (archive, format, index) = EXTENSIONS.iter()
+            .filter_map(|&(extension, format)| {
+                let archive = self.dir.join(format!("{}{}", name, extension));
+                self.indexes.get(&archive).map(|index| (archive, format, index))
+            })
+            .next()
+            .ok_or_else(not_found)?;
+
+        // The offsets of the members are wrong once the archive has changed.
+        if fs::metadata(&archive)?.modified().ok() != index.modified {
+            warn!("Refusing {} since {:?} changed after it was indexed", path, archive);
+            return Err(not_found());
+        }
+        let found = index.members.get(member).cloned();
+        Ok(Found {
+            archive,
+            format,
+            name: member.to_string(),
+            member: found.ok_or_else(not_found)?,
+        })
+    }
+
+    /// Opens the contents of a member of a `.tar.gz`, from the cache if possible.
+    fn open_compressed(&self, found: Found) -> io::Result<Box<dyn Read + Send>> {
+        let key = (found.archive, found.name);
+        if let Some(contents) = self.cache().get(&key) {
+            return Ok(Box::new(Cursor::new(SharedContents(contents))));
+        }
+
+        // A compressed archive cannot be seeked, so it is decompressed
+        // up to the start of the member.
+        let mut decoder = GzDecoder::new(File::open(&key.0)?);
+        io::copy(&mut (&mut decoder).take(found.member.offset), &mut io::sink())?;
+        let len = found.member.len;
+        let contents = if len <= self.cache().capacity {
+            Some(Vec::with_capacity(len as usize))
+        } else {
+            None
+        };
+        Ok(Box::new(CompressedMember {
+            reader: decoder.take(len),
+            contents,
+            remaining: len,
+            key,
+            cache: self.cache.clone(),
+        }))
+    }
+
+    fn cache(&self) -> MutexGuard<'_, MemberCache> {
+        lock_cache(&self.cache)
+    }
+}
+
+/// Locks the cache, ignoring a panic of another thread holding the lock
+/// since the cache is only an optimization.
+fn lock_cache(cache: &Mutex<MemberCache>) -> MutexGuard<'_, MemberCache> {
+    cache.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+impl Storage for ArchiveStorage {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read + Send>> {
+        Ok(self.open_with_metadata(path)?.0)
+    }
+
+    fn open_with_metadata(&self, path: &str) -> io::Result<(Box<dyn Read + Send>, Metadata)> {
+        let found = self.find(path)?;
+        let metadata = Metadata { len: found.member.len };
+        if found.format == ArchiveFormat::TarGz {
+            return Ok((self.open_compressed(found)?, metadata));
+        }
+
+        let member = found.member;
+        let mut file = File::open(found.archive)?;
+        file.seek(SeekFrom::Start(member.offset))?;
+        let reader: Box<dyn Read + Send> = match member.deflated_len {
+            Some(deflated_len) => {
+                Box::new(DeflateDecoder::new(file.take(deflated_len)).take(member.len))
+            }
+            None => Box::new(file.take(member.len)),
+        };
+        Ok((reader, metadata))
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        Ok(Metadata { len: self.find(path)?.member.len })
+    }
+
+    fn create(&self, _path: &str, _policy: WritePolicy) -> io::Result<Box<dyn StorageWriter>> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "archives are read-only"))
+    }
+}
+
+/// Returns the format of an archive from its file name,
+/// or `None` if the file is not an archive.
+fn archive_format(archive: &Path) -> Option<ArchiveFormat> {
+    let name = archive.file_name()?.to_str()?;
+    EXTENSIONS.iter()
+        .find(|&&(extension, _)| name.len() > extension.len() && name.ends_with(extension))
+        .map(|&(_, format)| format)
+}
+
+/// Reads where the regular files of an archive are stored.
+fn index_archive(archive: &Path, format: ArchiveFormat) -> io::Result<ArchiveIndex> {
+    let file = File::open(archive)?;
+    let modified = file.metadata()?.modified().ok();
+    let members = match format {
+        ArchiveFormat::Tar => index_tar(file)?,
+        ArchiveFormat::TarGz => index_tar(GzDecoder::new(file))?,
+        ArchiveFormat::Zip => index_zip(file)?,
+    };
+    Ok(ArchiveIndex { modified, members })
+}
+
+fn index_tar<R: Read>(reader: R) -> io::Result<HashMap<String, Member>> {
+    let mut members = HashMap::new();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = normalize_path(&entry.path()?.to_string_lossy());
+        members.insert(path,
+                       Member {
+                           offset: entry.raw_file_position(),
+                           len: entry.size(),
+                           deflated_len: None,
+                       });
+    }
+    Ok(members)
+}
+
+fn index_zip(file: File) -> io::Result<HashMap<String, Member>> {
+    let mut members = HashMap::new();
+    let mut archive = zip::ZipArchive::new(file)?;
+    for i in 0..archive.len() {
+        // Encrypted members and unsupported compression methods are refused here.
+        let file = match archive.by_index(i) {
+            Ok(file) => file,
+            Err(ZipError::UnsupportedArchive(reason)) => {
+                info!("Skipping member {} of zip archive: {}", i, reason);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if !file.is_file() {
+            continue;
+        }
+        let deflated_len = match file.compression() {
+            CompressionMethod::Stored => None,
+            CompressionMethod::Deflated => Some(file.compressed_size()),
+            method => {
+                info!("Skipping {} compressed with unsupported {}", file.name(), method);
+                continue;
+            }
+        };
+        members.insert(normalize_path(file.name()),
+                       Member {
+                           offset: file.data_start(),
+                           len: file.size(),
+                           deflated_len,
+                       });
+    }
+    Ok(members)
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "file does not exist")
+}
@@ -1,26 +1,102 @@
-#[macro_use]
-extern crate log;
-
 extern crate env_logger;
 extern crate tftp_server;
 
-use tftp_server::server::TftpServer;
+use tftp_server::archive::ArchiveStorage;
+use tftp_server::server::{AccessMode, BlockRollover, PortAllocation, TftpServerBuilder,
+                          WritePolicy};
 use std::env;
 use std::str::FromStr;
 use std::net::SocketAddr;
 
+/// Parses a write policy given as `reject`, `overwrite`,
+/// `replace` or `versioned:<number of versions to keep>`.
+fn parse_write_policy(policy: &str) -> WritePolicy {
+    match policy {
+        "reject" => WritePolicy::Reject,
+        "overwrite" => WritePolicy::Overwrite,
+        "replace" => WritePolicy::Replace,
+        policy if policy.starts_with("versioned:") => {
+            let retain = policy["versioned:".len()..]
+                .parse()
+                .expect("Error parsing the number of versions to keep");
+            WritePolicy::Versioned(retain)
+        }
+        _ => panic!("Unknown write policy: {}", policy),
+    }
+}
+
 fn main() {
     env_logger::init().unwrap();
 
-    let args: Vec<_> = env::args().collect();
-    let mut server: TftpServer;
-    if args.len() > 1 {
-        let port = args[1].clone();
-        let addr = format!("127.0.0.1:{}", port);
-        let socket_addr = SocketAddr::from_str(addr.as_str()).expect("Error parsing address");
-        server = TftpServer::new_from_addr(&socket_addr).expect("Error creating server");
-    } else {
-        server = TftpServer::new().expect("Error creating server");
+    let mut builder = TftpServerBuilder::new();
+    let mut has_addr = false;
+    let mut has_root = false;
+    let mut has_archives = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--root" => {
+                let dir = args.next().expect("Expected a directory after --root");
+                builder = builder.root_dir(dir);
+                has_root = true;
+            }
+            "--archives" => {
+                let dir = args.next().expect("Expected a directory after --archives");
+                let storage = ArchiveStorage::new(dir).expect("Error opening archive directory");
+                builder = builder.storage(storage).access_mode(AccessMode::ReadOnly);
+                has_archives = true;
+            }
+            "--write-policy" => {
+                let policy = args.next().expect("Expected a policy after --write-policy");
+                builder = builder.write_policy(parse_write_policy(&policy));
+            }
+            "--max-retries" => {
+                let retries = args.next().expect("Expected a number after --max-retries");
+                builder = builder.max_retries(retries.parse()
+                    .expect("Error parsing the number of retries"));
+            }
+            "--rollover" => {
+                let rollover = match args.next().as_deref() {
+                    Some("0") => BlockRollover::Zero,
+                    Some("1") => BlockRollover::One,
+                    _ => panic!("Expected 0 or 1 after --rollover"),
+                };
+                builder = builder.block_rollover(rollover);
+            }
+            "--port-range" => {
+                let range = args.next().expect("Expected a range like 50000-50099 after --port-range");
+                let mut ports = range.splitn(2, '-')
+                    .map(|port| port.parse::<u16>().expect("Error parsing the port range"));
+                let (start, end) = match (ports.next(), ports.next()) {
+                    (Some(start), Some(end)) => (start, end),
+                    _ => panic!("Expected a range like 50000-50099 after --port-range"),
+                };
+                builder = builder.port_range(start..=end);
+            }
+            "--sequential-ports" => {
+                builder = builder.port_allocation(PortAllocation::Sequential)
+            }
+            "--single-port" => builder = builder.single_port(true),
+            "--read-only" => builder = builder.access_mode(AccessMode::ReadOnly),
+            "--write-only" => builder = builder.access_mode(AccessMode::WriteOnly),
+            // Either a full address like [::]:69 or a port on the loopback interface.
+            addr => {
+                let socket_addr = SocketAddr::from_str(addr)
+                    .or_else(|_| SocketAddr::from_str(&format!("127.0.0.1:{}", addr)))
+                    .expect("Error parsing address");
+                builder = builder.addr(socket_addr);
+                has_addr = true;
+            }
+        }
+    }
+
+    // Both set the storage, so one would silently override the other.
+    if has_root && has_archives {
+        panic!("--archives cannot be used together with --root");
+    }
+
+    let mut server = builder.build().expect("Error creating server");
+    if !has_addr {
         println!("Server created at address: {:?}",
                  server.local_addr().unwrap());
     }
@@ -3,8 +3,18 @@ extern crate log;
 
 extern crate env_logger;
 extern crate byteorder;
+extern crate flate2;
 extern crate mio;
+extern crate mio_extras;
+extern crate net2;
 extern crate rand;
+extern crate tar;
+extern crate zip;
+#[cfg(unix)]
+extern crate libc;
 
+pub mod archive;
+pub mod netascii;
 pub mod packet;
 pub mod server;
+pub mod storage;
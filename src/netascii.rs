@@ -0,0 +1,142 @@
+use std::io;
+use std::io::{Read, Write};
+
+/// The carriage return byte.
+const CR: u8 = b'\r';
+/// The line feed byte.
+const LF: u8 = b'\n';
+/// The zero byte that follows a carriage return that is not part of a newline.
+const NUL: u8 = 0;
+
+/// Wraps a reader of a local file and translates its contents to
+/// netascii, replacing LF with CR LF and CR with CR NUL.
+pub struct NetasciiReader<R> {
+    inner: R,
+    /// The second byte of a translated pair that did not fit in the last read.
+    pending: Option<u8>,
+}
+
+impl<R: Read> NetasciiReader<R> {
+    pub fn new(inner: R) -> NetasciiReader<R> {
+        NetasciiReader {
+            inner,
+            pending: None,
+        }
+    }
+}
+
+impl<R: Read> Read for NetasciiReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut written = 0;
+        if let Some(byte) = self.pending.take() {
+            buf[0] = byte;
+            written = 1;
+            if buf.len() == 1 {
+                return Ok(written);
+            }
+        }
+
+        // Every byte translates to at most two bytes, so reading half of
+        // the remaining space overflows by at most one byte.
+        let remaining = buf.len() - written;
+        let mut raw = vec![0; (remaining / 2).max(1)];
+        let amount = match self.inner.read(&mut raw) {
+            Ok(amount) => amount,
+            Err(_) if written > 0 => return Ok(written),
+            Err(e) => return Err(e),
+        };
+
+        for &byte in &raw[0..amount] {
+            let (first, second) = match byte {
+                LF => (CR, Some(LF)),
+                CR => (CR, Some(NUL)),
+                byte => (byte, None),
+            };
+            for byte in Some(first).into_iter().chain(second) {
+                if written < buf.len() {
+                    buf[written] = byte;
+                    written += 1;
+                } else {
+                    self.pending = Some(byte);
+                }
+            }
+        }
+
+        Ok(written)
+    }
+}
+
+/// Wraps a writer of a local file and translates netascii written to it
+/// back to local text, replacing CR LF with LF and CR NUL with CR.
+/// A CR at the end of a write is held until the next byte arrives, so
+/// pairs split across DATA packets are translated correctly.
+/// A CR still held when the writer is finished is written as is, and is
+/// discarded if the writer is dropped without being finished.
+pub struct NetasciiWriter<W: Write> {
+    inner: W,
+    /// Whether the last byte written was a CR that has not been translated yet.
+    cr_pending: bool,
+}
+
+impl<W: Write> NetasciiWriter<W> {
+    pub fn new(inner: W) -> NetasciiWriter<W> {
+        NetasciiWriter {
+            inner,
+            cr_pending: false,
+        }
+    }
+
+    /// Returns a mutable reference to the wrapped writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Writes a CR that is still held back after the last write.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.cr_pending {
+            self.cr_pending = false;
+            self.inner.write_all(&[CR])?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for NetasciiWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut translated = Vec::with_capacity(buf.len() + 1);
+        for &byte in buf {
+            if self.cr_pending {
+                self.cr_pending = false;
+                match byte {
+                    LF => {
+                        translated.push(LF);
+                        continue;
+                    }
+                    NUL => {
+                        translated.push(CR);
+                        continue;
+                    }
+                    // A lone CR is not valid netascii and is kept as is.
+                    _ => translated.push(CR),
+                }
+            }
+
+            if byte == CR {
+                self.cr_pending = true;
+            } else {
+                translated.push(byte);
+            }
+        }
+
+        self.inner.write_all(&translated)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -1,14 +1,16 @@
-use std::{fmt, mem, result, str};
+use std::{fmt, result, str};
 use std::io::Cursor;
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 
 #[derive(Debug)]
 pub enum PacketErr {
     OverflowSize,
+    UnderflowSize,
     InvalidOpCode,
     StrOutOfBounds,
     OpCodeOutOfBounds,
     ErrCodeOutOfBounds,
+    InvalidMode,
     Utf8Error(str::Utf8Error),
 }
 
@@ -28,14 +30,19 @@ pub enum OpCode {
     DATA = 3,
     ACK = 4,
     ERROR = 5,
+    OACK = 6,
 }
 
 impl OpCode {
     pub fn from_u16(i: u16) -> Result<OpCode> {
-        if i >= OpCode::RRQ as u16 && i <= OpCode::ERROR as u16 {
-            Ok(unsafe { mem::transmute(i) })
-        } else {
-            Err(PacketErr::OpCodeOutOfBounds)
+        match i {
+            1 => Ok(OpCode::RRQ),
+            2 => Ok(OpCode::WRQ),
+            3 => Ok(OpCode::DATA),
+            4 => Ok(OpCode::ACK),
+            5 => Ok(OpCode::ERROR),
+            6 => Ok(OpCode::OACK),
+            _ => Err(PacketErr::OpCodeOutOfBounds),
         }
     }
 }
@@ -55,121 +62,150 @@ pub enum ErrorCode {
 
 impl ErrorCode {
     pub fn from_u16(i: u16) -> Result<ErrorCode> {
-        if i >= ErrorCode::NotDefined as u16 && i <= ErrorCode::NoUser as u16 {
-            Ok(unsafe { mem::transmute(i) })
-        } else {
-            Err(PacketErr::ErrCodeOutOfBounds)
+        match i {
+            0 => Ok(ErrorCode::NotDefined),
+            1 => Ok(ErrorCode::FileNotFound),
+            2 => Ok(ErrorCode::AccessViolation),
+            3 => Ok(ErrorCode::DiskFull),
+            4 => Ok(ErrorCode::IllegalTFTP),
+            5 => Ok(ErrorCode::UnknownID),
+            6 => Ok(ErrorCode::FileExists),
+            7 => Ok(ErrorCode::NoUser),
+            _ => Err(PacketErr::ErrCodeOutOfBounds),
         }
     }
 
-    /// Returns the string description of the error code.
-    pub fn to_string(&self) -> String {
-        (match *self {
-                ErrorCode::NotDefined => "Not defined, see error message (if any).",
-                ErrorCode::FileNotFound => "File not found.",
-                ErrorCode::AccessViolation => "Access violation.",
-                ErrorCode::DiskFull => "Disk full or allocation exceeded.",
-                ErrorCode::IllegalTFTP => "Illegal TFTP operation.",
-                ErrorCode::UnknownID => "Unknown transfer ID.",
-                ErrorCode::FileExists => "File already exists.",
-                ErrorCode::NoUser => "No such user.",
-            })
-            .to_string()
-    }
-
     /// Returns the ERROR packet with the error code and
     /// the default description as the error message.
     pub fn to_packet(&self) -> Packet {
-        let msg = self.to_string();
         Packet::ERROR {
             code: *self,
-            msg: msg,
+            msg: self.to_string(),
         }
     }
 }
 
-pub const MODES: [&'static str; 3] = ["netascii", "octet", "mail"];
-pub const MAX_PACKET_SIZE: usize = 1024;
-pub const MAX_DATA_SIZE: usize = 516;
-
-/// The byte representation of a packet. Because many packets can
-/// be smaller than the maximum packet size, it contains a length
-/// parameter so that the actual packet size can be determined.
-pub struct PacketData {
-    bytes: [u8; MAX_PACKET_SIZE],
-    len: usize,
+impl fmt::Display for ErrorCode {
+    /// Writes the string description of the error code.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ErrorCode::NotDefined => "Not defined, see error message (if any).",
+            ErrorCode::FileNotFound => "File not found.",
+            ErrorCode::AccessViolation => "Access violation.",
+            ErrorCode::DiskFull => "Disk full or allocation exceeded.",
+            ErrorCode::IllegalTFTP => "Illegal TFTP operation.",
+            ErrorCode::UnknownID => "Unknown transfer ID.",
+            ErrorCode::FileExists => "File already exists.",
+            ErrorCode::NoUser => "No such user.",
+        })
+    }
 }
 
-impl PacketData {
-    pub fn new(bytes: [u8; MAX_PACKET_SIZE], len: usize) -> PacketData {
-        PacketData {
-            bytes: bytes,
-            len: len,
-        }
-    }
+pub const MODES: [&str; 3] = ["netascii", "octet", "mail"];
 
-    /// Returns a byte slice that can be sent through a socket.
-    pub fn to_slice<'a>(&'a self) -> &'a [u8] {
-        &self.bytes[0..self.len]
-    }
+/// The transfer mode of a RRQ or WRQ packet.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Mode {
+    NetAscii,
+    Octet,
+    Mail,
 }
 
-impl Clone for PacketData {
-    fn clone(&self) -> PacketData {
-        let mut bytes = [0; MAX_PACKET_SIZE];
-        for i in 0..MAX_PACKET_SIZE {
-            bytes[i] = self.bytes[i];
+impl Mode {
+    /// Parses a mode name, ignoring case as required by RFC 1350.
+    pub fn from_name(name: &str) -> Result<Mode> {
+        match MODES.iter().position(|mode| mode.eq_ignore_ascii_case(name)) {
+            Some(0) => Ok(Mode::NetAscii),
+            Some(1) => Ok(Mode::Octet),
+            Some(2) => Ok(Mode::Mail),
+            _ => Err(PacketErr::InvalidMode),
         }
+    }
 
-        PacketData {
-            bytes: bytes,
-            len: self.len,
+    /// Returns the name of the mode as it is sent in a packet.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Mode::NetAscii => MODES[0],
+            Mode::Octet => MODES[1],
+            Mode::Mail => MODES[2],
         }
     }
 }
 
-/// A wrapper around the data that is to be sent in a TFTP DATA packet
-/// so that the data can be cloned and compared for equality.
-pub struct DataBytes(pub [u8; 512]);
-
-impl PartialEq for DataBytes {
-    fn eq(&self, other: &DataBytes) -> bool {
-        for i in 0..512 {
-            if self.0[i] != other.0[i] {
-                return false;
-            }
-        }
-
-        true
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
     }
 }
 
-impl Clone for DataBytes {
-    fn clone(&self) -> DataBytes {
-        let mut bytes = [0; 512];
-        for i in 0..512 {
-            bytes[i] = self.0[i];
-        }
+/// The block size used when no blksize option was negotiated.
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+/// The smallest block size allowed by RFC 2348.
+pub const MIN_BLOCK_SIZE: usize = 8;
+/// The largest block size allowed by RFC 2348.
+pub const MAX_BLOCK_SIZE: usize = 65464;
+/// The size of the largest possible packet, a DATA packet with the largest block size.
+pub const MAX_PACKET_SIZE: usize = MAX_BLOCK_SIZE + 4;
+/// The size of a full DATA packet with the default block size.
+pub const MAX_DATA_SIZE: usize = DEFAULT_BLOCK_SIZE + 4;
+
+/// The byte representation of a packet.
+#[derive(Clone)]
+pub struct PacketData {
+    bytes: Vec<u8>,
+}
+
+impl PacketData {
+    /// Creates the packet bytes from the first `len` bytes of the buffer.
+    pub fn new(bytes: &[u8], len: usize) -> PacketData {
+        PacketData { bytes: bytes[0..len].to_vec() }
+    }
 
-        DataBytes(bytes)
+    /// Returns a byte slice that can be sent through a socket.
+    pub fn to_slice(&self) -> &[u8] {
+        &self.bytes
     }
 }
 
+/// A wrapper around the data that is to be sent in a TFTP DATA packet
+/// so that the data can be cloned and compared for equality.
+#[derive(PartialEq, Clone)]
+pub struct DataBytes(pub Vec<u8>);
+
 impl fmt::Debug for DataBytes {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", String::from_utf8_lossy(&self.0[..]))
     }
 }
 
+/// An option/value pair appended to a RRQ, WRQ or OACK packet
+/// as described in RFC 2347. Option names are case insensitive.
+#[derive(PartialEq, Clone, Debug)]
+pub struct TftpOption {
+    pub name: String,
+    pub value: String,
+}
+
+impl TftpOption {
+    pub fn new<N: Into<String>, V: Into<String>>(name: N, value: V) -> TftpOption {
+        TftpOption {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub enum Packet {
     RRQ {
         filename: String,
-        mode: String,
+        mode: Mode,
+        options: Vec<TftpOption>,
     },
     WRQ {
         filename: String,
-        mode: String,
+        mode: Mode,
+        options: Vec<TftpOption>,
     },
     DATA {
         block_num: u16,
@@ -181,17 +217,26 @@ pub enum Packet {
         code: ErrorCode,
         msg: String,
     },
+    OACK(Vec<TftpOption>),
 }
 
 impl Packet {
     /// Creates and returns a packet parsed from its byte representation.
     pub fn read(bytes: PacketData) -> Result<Packet> {
+        if bytes.bytes.len() < 2 {
+            return Err(PacketErr::UnderflowSize);
+        }
         let opcode = OpCode::from_u16(merge_bytes(bytes.bytes[0], bytes.bytes[1]))?;
+        let has_header = bytes.bytes.len() >= 4;
         match opcode {
+            OpCode::DATA | OpCode::ACK | OpCode::ERROR if !has_header => {
+                Err(PacketErr::UnderflowSize)
+            }
             OpCode::RRQ | OpCode::WRQ => read_rw_packet(opcode, bytes),
             OpCode::DATA => read_data_packet(bytes),
             OpCode::ACK => read_ack_packet(bytes),
             OpCode::ERROR => read_error_packet(bytes),
+            OpCode::OACK => read_oack_packet(bytes),
         }
     }
 
@@ -203,17 +248,23 @@ impl Packet {
             Packet::DATA { .. } => OpCode::DATA,
             Packet::ACK(_) => OpCode::ACK,
             Packet::ERROR { .. } => OpCode::ERROR,
+            Packet::OACK(_) => OpCode::OACK,
         }
     }
 
     /// Consumes the packet and returns the packet in byte representation.
     pub fn bytes(self) -> Result<PacketData> {
         match self {
-            Packet::RRQ { filename, mode } => rw_packet_bytes(OpCode::RRQ, filename, mode),
-            Packet::WRQ { filename, mode } => rw_packet_bytes(OpCode::WRQ, filename, mode),
+            Packet::RRQ { filename, mode, options } => {
+                rw_packet_bytes(OpCode::RRQ, filename, mode, options)
+            }
+            Packet::WRQ { filename, mode, options } => {
+                rw_packet_bytes(OpCode::WRQ, filename, mode, options)
+            }
             Packet::DATA { block_num, data, len } => data_packet_bytes(block_num, data.0, len),
             Packet::ACK(block_num) => ack_packet_bytes(block_num),
             Packet::ERROR { code, msg } => error_packet_bytes(code, msg),
+            Packet::OACK(options) => oack_packet_bytes(options),
         }
     }
 }
@@ -235,37 +286,53 @@ fn merge_bytes(num1: u8, num2: u8) -> u16 {
 /// Reads bytes from the packet bytes starting from the given index
 /// until the zero byte and returns a string containing the bytes read.
 fn read_string(bytes: &PacketData, start: usize) -> Result<(String, usize)> {
-    let mut result_bytes = Vec::new();
-    let mut counter = start;
-    while bytes.bytes[counter] != 0 {
-        result_bytes.push(bytes.bytes[counter]);
-
-        counter += 1;
-        if counter >= bytes.len {
-            return Err(PacketErr::StrOutOfBounds);
-        }
+    let slice = bytes.to_slice();
+    if start >= slice.len() {
+        return Err(PacketErr::StrOutOfBounds);
+    }
+    let len = match slice[start..].iter().position(|&b| b == 0) {
+        Some(len) => len,
+        None => return Err(PacketErr::StrOutOfBounds),
+    };
+
+    let result_str = str::from_utf8(&slice[start..start + len])?.to_string();
+    Ok((result_str, start + len + 1))
+}
+
+/// Reads option/value string pairs from the given index
+/// until the end of the packet.
+fn read_options(bytes: &PacketData, start: usize) -> Result<Vec<TftpOption>> {
+    let mut options = Vec::new();
+    let mut pos = start;
+    while pos < bytes.bytes.len() {
+        let (name, value_pos) = read_string(bytes, pos)?;
+        let (value, end_pos) = read_string(bytes, value_pos)?;
+        options.push(TftpOption { name, value });
+        pos = end_pos;
     }
-    counter += 1;
 
-    let result_str = str::from_utf8(result_bytes.as_slice())?.to_string();
-    Ok((result_str, counter))
+    Ok(options)
 }
 
 fn read_rw_packet(code: OpCode, bytes: PacketData) -> Result<Packet> {
     let (filename, end_pos) = read_string(&bytes, 2)?;
-    let (mode, _) = read_string(&bytes, end_pos)?;
+    let (mode, end_pos) = read_string(&bytes, end_pos)?;
+    let mode = Mode::from_name(&mode)?;
+    let options = read_options(&bytes, end_pos)?;
 
     match code {
         OpCode::RRQ => {
             Ok(Packet::RRQ {
-                filename: filename,
-                mode: mode,
+                filename,
+                mode,
+                options,
             })
         }
         OpCode::WRQ => {
             Ok(Packet::WRQ {
-                filename: filename,
-                mode: mode,
+                filename,
+                mode,
+                options,
             })
         }
         _ => Err(PacketErr::InvalidOpCode),
@@ -274,15 +341,12 @@ fn read_rw_packet(code: OpCode, bytes: PacketData) -> Result<Packet> {
 
 fn read_data_packet(bytes: PacketData) -> Result<Packet> {
     let block_num = merge_bytes(bytes.bytes[2], bytes.bytes[3]);
-    let mut data = [0; 512];
-    for i in 0..512 {
-        data[i] = bytes.bytes[i + 4];
-    }
+    let data = bytes.bytes[4..].to_vec();
 
     Ok(Packet::DATA {
-        block_num: block_num,
+        block_num,
+        len: data.len(),
         data: DataBytes(data),
-        len: bytes.len - 4,
     })
 }
 
@@ -297,107 +361,103 @@ fn read_error_packet(bytes: PacketData) -> Result<Packet> {
 
     Ok(Packet::ERROR {
         code: error_code,
-        msg: msg,
+        msg,
     })
 }
 
-fn rw_packet_bytes(packet: OpCode, filename: String, mode: String) -> Result<PacketData> {
-    if filename.len() + mode.len() > MAX_PACKET_SIZE {
-        return Err(PacketErr::OverflowSize);
-    }
+fn read_oack_packet(bytes: PacketData) -> Result<Packet> {
+    Ok(Packet::OACK(read_options(&bytes, 2)?))
+}
 
-    let mut bytes = [0; MAX_PACKET_SIZE];
+/// Appends a two byte unsigned integer to the packet bytes.
+fn write_u16(bytes: &mut Vec<u8>, num: u16) {
+    let (b1, b2) = split_into_bytes(num);
+    bytes.push(b1);
+    bytes.push(b2);
+}
 
-    let (b1, b2) = split_into_bytes(packet as u16);
-    bytes[0] = b1;
-    bytes[1] = b2;
+/// Appends a zero terminated string to the packet bytes.
+fn write_string(bytes: &mut Vec<u8>, string: &str) {
+    bytes.extend_from_slice(string.as_bytes());
+    bytes.push(0);
+}
 
-    let mut index = 2;
-    for byte in filename.bytes() {
-        bytes[index] = byte;
-        index += 1;
+/// Appends option/value string pairs to the packet bytes.
+fn write_options(bytes: &mut Vec<u8>, options: &[TftpOption]) {
+    for option in options {
+        write_string(bytes, &option.name);
+        write_string(bytes, &option.value);
     }
+}
 
-    index += 1;
-    for byte in mode.bytes() {
-        bytes[index] = byte;
-        index += 1;
+/// Wraps the packet bytes, checking that they fit within the maximum packet size.
+fn packet_data(bytes: Vec<u8>) -> Result<PacketData> {
+    if bytes.len() > MAX_PACKET_SIZE {
+        return Err(PacketErr::OverflowSize);
     }
-    index += 1;
 
-    Ok(PacketData::new(bytes, index))
+    Ok(PacketData { bytes })
 }
 
-fn data_packet_bytes(block_num: u16, data: [u8; 512], data_len: usize) -> Result<PacketData> {
-    let mut bytes = [0; MAX_PACKET_SIZE];
-
-    let (b1, b2) = split_into_bytes(OpCode::DATA as u16);
-    bytes[0] = b1;
-    bytes[1] = b2;
-
-    let (b3, b4) = split_into_bytes(block_num);
-    bytes[2] = b3;
-    bytes[3] = b4;
+fn rw_packet_bytes(packet: OpCode,
+                   filename: String,
+                   mode: Mode,
+                   options: Vec<TftpOption>)
+                   -> Result<PacketData> {
+    let mut bytes = Vec::new();
+    write_u16(&mut bytes, packet as u16);
+    write_string(&mut bytes, &filename);
+    write_string(&mut bytes, mode.name());
+    write_options(&mut bytes, &options);
+
+    packet_data(bytes)
+}
 
-    let mut index = 4;
-    for i in 0..data_len {
-        bytes[index] = data[i];
-        index += 1;
+fn data_packet_bytes(block_num: u16, data: Vec<u8>, data_len: usize) -> Result<PacketData> {
+    if data_len > data.len() {
+        return Err(PacketErr::OverflowSize);
     }
 
-    Ok(PacketData::new(bytes, index))
+    let mut bytes = Vec::with_capacity(4 + data_len);
+    write_u16(&mut bytes, OpCode::DATA as u16);
+    write_u16(&mut bytes, block_num);
+    bytes.extend_from_slice(&data[0..data_len]);
+
+    packet_data(bytes)
 }
 
 fn ack_packet_bytes(block_num: u16) -> Result<PacketData> {
-    let mut bytes = [0; MAX_PACKET_SIZE];
-
-    let (b1, b2) = split_into_bytes(OpCode::ACK as u16);
-    bytes[0] = b1;
-    bytes[1] = b2;
+    let mut bytes = Vec::with_capacity(4);
+    write_u16(&mut bytes, OpCode::ACK as u16);
+    write_u16(&mut bytes, block_num);
 
-    let (b3, b4) = split_into_bytes(block_num);
-    bytes[2] = b3;
-    bytes[3] = b4;
-
-    Ok(PacketData::new(bytes, 4))
+    packet_data(bytes)
 }
 
 fn error_packet_bytes(code: ErrorCode, msg: String) -> Result<PacketData> {
-    if msg.len() + 5 > MAX_PACKET_SIZE {
-        return Err(PacketErr::OverflowSize);
-    }
-
-    let mut bytes = [0; MAX_PACKET_SIZE];
+    let mut bytes = Vec::new();
+    write_u16(&mut bytes, OpCode::ERROR as u16);
+    write_u16(&mut bytes, code as u16);
+    write_string(&mut bytes, &msg);
 
-    let (b1, b2) = split_into_bytes(OpCode::ERROR as u16);
-    bytes[0] = b1;
-    bytes[1] = b2;
-
-    let (b3, b4) = split_into_bytes(code as u16);
-    bytes[2] = b3;
-    bytes[3] = b4;
+    packet_data(bytes)
+}
 
-    let mut index = 4;
-    for byte in msg.bytes() {
-        bytes[index] = byte;
-        index += 1;
-    }
-    index += 1;
+fn oack_packet_bytes(options: Vec<TftpOption>) -> Result<PacketData> {
+    let mut bytes = Vec::new();
+    write_u16(&mut bytes, OpCode::OACK as u16);
+    write_options(&mut bytes, &options);
 
-    Ok(PacketData::new(bytes, index))
+    packet_data(bytes)
 }
 
 macro_rules! read_string {
     ($name:ident, $bytes:expr, $start_pos:expr, $string:expr, $end_pos:expr) => {
         #[test]
         fn $name() {
-            let mut bytes = [0; MAX_PACKET_SIZE];
-            let seed_bytes = $bytes.chars().collect::<Vec<_>>();
-            for i in 0..$bytes.len() {
-                bytes[i] = seed_bytes[i] as u8;
-            }
+            let bytes = $bytes.chars().map(|c| c as u8).collect::<Vec<_>>();
 
-            let result = read_string(&PacketData::new(bytes, seed_bytes.len()), $start_pos);
+            let result = read_string(&PacketData::new(&bytes, bytes.len()), $start_pos);
             assert!(result.is_ok());
             let _ = result.map(|(string, end_pos)| {
                 assert_eq!(string, $string);
@@ -1,33 +1,48 @@
 use mio::*;
-use mio::timer::{Timer, TimerError, Timeout};
-use mio::udp::UdpSocket;
-use packet::{ErrorCode, MAX_PACKET_SIZE, DataBytes, Packet, PacketData, PacketErr};
+use mio::net::UdpSocket;
+use mio_extras::timer::{Timer, Timeout};
+use net2::UdpBuilder;
+use netascii::{NetasciiReader, NetasciiWriter};
+use packet::{ErrorCode, DEFAULT_BLOCK_SIZE, MAX_BLOCK_SIZE, MAX_PACKET_SIZE, MIN_BLOCK_SIZE,
+             DataBytes, Mode, Packet, PacketData, PacketErr, TftpOption};
 use rand;
 use rand::Rng;
-use std::collections::HashMap;
-use std::fs;
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::io;
 use std::io::{Read, Write};
 use std::net;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::result;
-use std::str::FromStr;
-use std::time::Duration;
-use std::u16;
+use std::time::{Duration, Instant};
+use storage::{normalize_path, LocalStorage, Storage, StorageWriter};
 
-/// Timeout time until packet is re-sent.
+pub use storage::WritePolicy;
+
+/// Timeout time until packet is re-sent if the client did not request one
+/// and no round-trip time has been measured yet.
 const TIMEOUT: u64 = 3;
-/// The token used by the server UDP socket.
-const SERVER: Token = Token(0);
+/// The smallest retransmission timeout derived from measured round-trip times.
+const MIN_RTO: Duration = Duration::from_millis(200);
+/// The largest retransmission timeout, including backoff.
+const MAX_RTO: Duration = Duration::from_secs(60);
+/// The smallest timeout in seconds a client may request.
+const MIN_TIMEOUT: u64 = 1;
+/// The largest timeout in seconds a client may request.
+const MAX_TIMEOUT: u64 = 255;
+/// The largest windowsize accepted from a client, which bounds the number
+/// of blocks read and kept in memory for a transfer at once.
+const MAX_WINDOW_SIZE: u16 = 64;
+/// The number of consecutive timeouts after which a transfer is given up if not configured.
+const MAX_RETRIES: u32 = 5;
 /// The token used by the timer.
-const TIMER: Token = Token(1);
+const TIMER: Token = Token(0);
 
 #[derive(Debug)]
 pub enum TftpError {
     PacketError(PacketErr),
     IoError(io::Error),
-    TimerError(TimerError),
     /// Error defined within the TFTP spec with an usigned integer
     /// error code. The server should reply with an error packet
     /// to the given socket address when handling this error.
@@ -36,7 +51,7 @@ pub enum TftpError {
     /// find a random open UDP port within 100 tries.
     NoOpenSocket,
     /// Error when the connection is to be closed normally
-    /// like when a data packet is smaller than 516 bytes.
+    /// like when a data packet is smaller than the block size.
     /// The server should just close the connection without
     /// propagating up the error.
     CloseConnection,
@@ -44,6 +59,19 @@ pub enum TftpError {
     /// of the source address when receiving from a socket.
     /// This error should be ignored by the server.
     NoneFromSocket,
+    /// Error when a connection socket receives a packet from an address
+    /// other than the client's (RFC 1350, section 4). The server should
+    /// reply to that address with an unknown transfer ID error and
+    /// continue the transfer undisturbed.
+    UnknownTid(SocketAddr),
+    /// Error when no socket can be created for a new transfer because every
+    /// port of the configured port range is in use by a connection
+    /// or another program.
+    PortRangeExhausted,
+    /// Error when the client aborted the transfer with an error packet.
+    /// The server should close the connection without replying, since
+    /// error packets are not answered (RFC 1350, section 7).
+    AbortedByClient,
 }
 
 impl From<io::Error> for TftpError {
@@ -58,18 +86,329 @@ impl From<PacketErr> for TftpError {
     }
 }
 
-impl From<TimerError> for TftpError {
-    fn from(err: TimerError) -> TftpError {
-        TftpError::TimerError(err)
+pub type Result<T> = result::Result<T, TftpError>;
+
+/// The transfer parameters of a connection that
+/// can be changed through option negotiation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TransferOptions {
+    /// The number of data bytes in a full DATA packet (RFC 2348).
+    blksize: usize,
+    /// The size of the file being transferred if it was announced (RFC 2349).
+    tsize: Option<u64>,
+    /// The number of seconds to wait before resending the last packet if the
+    /// client asked for a fixed timeout (RFC 2349).
+    timeout: Option<u64>,
+    /// The number of consecutive blocks sent before waiting for an ACK (RFC 7440).
+    windowsize: u16,
+    /// The block number that follows block 65535.
+    rollover: BlockRollover,
+}
+
+impl Default for TransferOptions {
+    fn default() -> TransferOptions {
+        TransferOptions {
+            blksize: DEFAULT_BLOCK_SIZE,
+            tsize: None,
+            timeout: None,
+            windowsize: 1,
+            rollover: BlockRollover::default(),
+        }
     }
 }
 
-pub type Result<T> = result::Result<T, TftpError>;
+/// Estimates the round-trip time of a connection to derive its
+/// retransmission timeout, following Jacobson and Karels (RFC 6298).
+#[derive(Clone, Copy, Debug)]
+struct RttEstimator {
+    /// The smoothed round-trip time, once a round trip has been measured.
+    srtt: Option<Duration>,
+    /// The smoothed variation of the round-trip time.
+    rttvar: Duration,
+    /// The retransmission timeout before backoff is applied.
+    rto: Duration,
+}
+
+impl RttEstimator {
+    fn new() -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: Duration::from_secs(TIMEOUT),
+        }
+    }
+
+    /// Updates the estimate with a measured round-trip time.
+    fn sample(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Returns the retransmission timeout after the given number of
+    /// timeouts in a row, doubling it for every timeout.
+    fn timeout(&self, retries: u32) -> Duration {
+        self.rto.saturating_mul(2u32.saturating_pow(retries)).min(MAX_RTO)
+    }
+}
+
+/// The block number that follows block 65535 in transfers of more
+/// than 65535 blocks. Clients can choose it with the rollover option.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum BlockRollover {
+    /// Block 65535 is followed by block 0, which most clients expect.
+    #[default]
+    Zero,
+    /// Block 65535 is followed by block 1.
+    One,
+}
+
+impl BlockRollover {
+    /// Increments the block number, rolling over after block 65535.
+    pub fn incr(self, block_num: &mut u16) {
+        *block_num = match (*block_num, self) {
+            (u16::MAX, BlockRollover::Zero) => 0,
+            (u16::MAX, BlockRollover::One) => 1,
+            (num, _) => num + 1,
+        };
+    }
+}
+
+/// The kinds of requests a server accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum AccessMode {
+    /// Both RRQ and WRQ packets are accepted.
+    #[default]
+    ReadWrite,
+    /// Only RRQ packets are accepted, every WRQ is refused.
+    ReadOnly,
+    /// Only WRQ packets are accepted, every RRQ is refused.
+    WriteOnly,
+}
+
+/// Produces the contents of a requested file on the fly from the path of the
+/// file and the address of the client. Returns `None` to leave the request
+/// to the next handler or the storage. Errors are reported to the client like
+/// the errors of a `Storage`, with unknown errors reported as file not found.
+pub type ReadHandler = Box<dyn Fn(&str, &SocketAddr) -> Option<io::Result<Vec<u8>>> + Send>;
+
+/// Decides whether to take an upload from the path of the file, the transfer
+/// mode and the address of the client. Returns a writer that receives the
+/// uploaded data, an error to refuse the upload, or `None` to leave it to the
+/// next handler or the storage. Netascii uploads are translated to local text
+/// before they reach the writer. When the transfer ends, the writer is
+/// finalized after the last block arrived or aborted if the transfer failed.
+pub type WriteHandler = Box<dyn Fn(&str, Mode, &SocketAddr)
+                                   -> Option<io::Result<Box<dyn StorageWriter>>> + Send>;
+
+/// How the port of a new transfer socket is picked from the port range.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum PortAllocation {
+    /// Ports are tried starting at a random port of the range.
+    #[default]
+    Random,
+    /// Ports are handed out in order, continuing after the last port handed out.
+    Sequential,
+}
+
+/// Hands out the ports of the sockets created for transfers.
+struct PortPool {
+    /// The ports transfer sockets are bound to. If not set,
+    /// any open port is used.
+    range: Option<RangeInclusive<u16>>,
+    /// How ports are picked from the range.
+    allocation: PortAllocation,
+    /// The port tried first by the next sequential allocation.
+    next: u16,
+    /// The ports of the range that open connections are bound to.
+    in_use: HashSet<u16>,
+}
+
+impl PortPool {
+    fn new(range: Option<RangeInclusive<u16>>, allocation: PortAllocation) -> PortPool {
+        let next = range.as_ref().map_or(0, |range| *range.start());
+        PortPool {
+            range,
+            allocation,
+            next,
+            in_use: HashSet::new(),
+        }
+    }
+
+    /// Creates a socket for a transfer on the given address, bound to a port
+    /// of the range that is not in use, and returns it with its port.
+    fn bind(&mut self, ip: IpAddr) -> Result<(net::UdpSocket, u16)> {
+        let (start, end) = match self.range {
+            Some(ref range) => (u32::from(*range.start()), u32::from(*range.end())),
+            None => {
+                let socket = create_socket_at(ip, Some(Duration::from_secs(TIMEOUT)))?;
+                let port = socket.local_addr()?.port();
+                return Ok((socket, port));
+            }
+        };
+        if start > end {
+            return Err(TftpError::PortRangeExhausted);
+        }
+
+        let len = end - start + 1;
+        let first = match self.allocation {
+            PortAllocation::Random => rand::thread_rng().gen_range(0, len),
+            PortAllocation::Sequential => u32::from(self.next) - start,
+        };
+        for i in 0..len {
+            let port = (start + (first + i) % len) as u16;
+            if self.in_use.contains(&port) {
+                continue;
+            }
+            if let Ok(socket) = net::UdpSocket::bind(SocketAddr::new(ip, port)) {
+                self.in_use.insert(port);
+                self.next = (start + (first + i + 1) % len) as u16;
+                return Ok((socket, port));
+            }
+        }
+        Err(TftpError::PortRangeExhausted)
+    }
+
+    /// Makes the port of a closed connection available again.
+    fn release(&mut self, port: u16) {
+        self.in_use.remove(&port);
+    }
+}
+
+/// The settings of a server that apply to all of its connections.
+#[derive(Clone, Debug)]
+struct ServerConfig {
+    /// The kinds of requests the server accepts.
+    access_mode: AccessMode,
+    /// What happens when a WRQ asks for a file that already exists.
+    write_policy: WritePolicy,
+    /// The block number that follows block 65535 unless the client chooses one.
+    rollover: BlockRollover,
+    /// The largest file size in bytes that a client may announce in a WRQ.
+    max_upload_size: Option<u64>,
+    /// The number of times the unacknowledged packets of a connection are resent
+    /// without hearing back from the client before the transfer is aborted.
+    max_retries: u32,
+    /// Whether transfers are served from the server socket that received
+    /// the request instead of a new socket for every transfer.
+    single_port: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            access_mode: AccessMode::default(),
+            write_policy: WritePolicy::default(),
+            rollover: BlockRollover::default(),
+            max_upload_size: None,
+            max_retries: MAX_RETRIES,
+            single_port: false,
+        }
+    }
+}
+
+/// The open file of a connection, translated from or to netascii
+/// if the transfer mode requested it.
+enum TransferFile {
+    /// The file being read from for a RRQ.
+    Reader(Box<dyn Read + Send>),
+    /// The file being written to for a WRQ.
+    Writer(Upload),
+}
+
+/// The upload a WRQ is written to, translated from netascii
+/// if the transfer mode requested it.
+enum UploadWriter {
+    Octet(Box<dyn StorageWriter>),
+    NetAscii(NetasciiWriter<Box<dyn StorageWriter>>),
+}
+
+/// A file being uploaded with a WRQ. The upload is aborted
+/// if it is dropped before it was finished.
+struct Upload {
+    writer: UploadWriter,
+    /// Whether the upload was finalized.
+    finished: bool,
+    /// The number of bytes received from the client so far.
+    received: u64,
+    /// The largest number of bytes the client may send, if limited.
+    limit: Option<u64>,
+}
+
+impl Upload {
+    fn new(writer: UploadWriter, limit: Option<u64>) -> Upload {
+        Upload {
+            writer,
+            finished: false,
+            received: 0,
+            limit,
+        }
+    }
+
+    /// Returns the upload of the storage.
+    fn storage_writer(&mut self) -> &mut dyn StorageWriter {
+        match self.writer {
+            UploadWriter::Octet(ref mut writer) => &mut **writer,
+            UploadWriter::NetAscii(ref mut writer) => &mut **writer.get_mut(),
+        }
+    }
+
+    /// Completes the upload after the last block has been written.
+    fn finish(&mut self) -> io::Result<()> {
+        if let UploadWriter::NetAscii(ref mut writer) = self.writer {
+            writer.finish()?;
+        }
+        // A failed upload is cleaned up by the storage writer itself.
+        self.finished = true;
+        self.storage_writer().finalize()
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.storage_writer().abort();
+        }
+    }
+}
+
+impl Write for Upload {
+    /// Writes received data to the upload, failing with `StorageFull` once
+    /// the client sent more than the limit, whatever tsize it announced.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.limit.is_some_and(|limit| self.received + buf.len() as u64 > limit) {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, "upload is too large"));
+        }
+        let amount = match self.writer {
+            UploadWriter::Octet(ref mut writer) => writer.write(buf)?,
+            UploadWriter::NetAscii(ref mut writer) => writer.write(buf)?,
+        };
+        self.received += amount as u64;
+        Ok(amount)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer {
+            UploadWriter::Octet(ref mut writer) => writer.flush(),
+            UploadWriter::NetAscii(ref mut writer) => writer.flush(),
+        }
+    }
+}
 
 /// The state contained within a connection.
 /// A connection is started when a server socket receives
 /// a RRQ or a WRQ packet and ends when the connection socket
-/// receives a DATA packet less than 516 bytes or if the connection
+/// receives the ACK for the last block of a RRQ, shortly after it receives
+/// a DATA packet smaller than the block size for a WRQ, or if the connection
 /// socket receives an invalid packet.
 struct ConnectionState {
     /// The UDP socket for the connection that receives ACK, DATA, or ERROR packets.
@@ -77,17 +416,74 @@ struct ConnectionState {
     /// The open file either being written to or read from during the transfer.
     /// If the connection was started with a RRQ, the file would be read from, if it
     /// was started with a WRQ, the file would be written to.
-    file: File,
+    file: TransferFile,
     /// The timeout for the last packet. Every time a new packet is received, the
     /// timeout is reset.
     timeout: Timeout,
-    /// The current block number of the transfer. If the block numbers of the received packet
-    /// and the current block number do not match, the connection is closed.
+    /// The block number of the last block acknowledged by the client for a RRQ,
+    /// or the last block received in order from the client for a WRQ.
     block_num: u16,
-    /// The last packet sent. This is used when a timeout happens to resend the last packet.
-    last_packet: Packet,
+    /// The packets sent that have not been acknowledged yet. For a RRQ these are the
+    /// DATA packets of the current window, for a WRQ this is the ACK for the last
+    /// block received. If options were negotiated this is the OACK until the first
+    /// ACK or DATA arrives. The whole window is resent when a timeout happens.
+    window: VecDeque<Packet>,
+    /// The number of blocks received for a WRQ since the last ACK was sent.
+    unacked: u16,
+    /// Whether the last block of the file has been read for a RRQ.
+    eof: bool,
+    /// Whether the last block of a WRQ has been received and acknowledged.
+    /// The connection is kept open for a while afterwards to acknowledge
+    /// the block again if the client resends it because the ACK was lost.
+    complete: bool,
+    /// The number of timeouts in a row since the last packet from the client.
+    retries: u32,
+    /// The round-trip time estimate used for the retransmission timeout.
+    rtt: RttEstimator,
+    /// The block number of the reply that completes the round trip being timed,
+    /// with the time the packet it answers was sent. Round trips of resent
+    /// packets are not timed because the reply could answer either copy.
+    rtt_probe: Option<(u16, Instant)>,
     /// The address of the client socket to reply to.
     addr: SocketAddr,
+    /// Whether `conn` is a clone of a server socket in single-port mode
+    /// that is shared with other connections, rather than the connection's own socket.
+    shared: bool,
+    /// The options negotiated for the transfer.
+    options: TransferOptions,
+}
+
+impl ConnectionState {
+    /// Starts timing a round trip that completes when the reply
+    /// with the given block number arrives.
+    fn start_rtt_probe(&mut self, block_num: u16) {
+        self.rtt_probe = Some((block_num, Instant::now()));
+    }
+
+    /// Completes the round trip being timed if it is completed
+    /// by the reply with the given block number.
+    fn finish_rtt_probe(&mut self, block_num: u16) {
+        if let Some((expected, sent)) = self.rtt_probe {
+            if expected == block_num {
+                self.rtt.sample(sent.elapsed());
+                self.rtt_probe = None;
+            }
+        }
+    }
+
+    /// Returns how long to wait for the client before resending, which
+    /// is the negotiated timeout if there is one and the estimated
+    /// retransmission timeout with backoff otherwise. A completed WRQ
+    /// waits for a resent last block for the negotiated or default timeout.
+    fn retransmit_timeout(&self) -> Duration {
+        if self.complete {
+            return Duration::from_secs(self.options.timeout.unwrap_or(TIMEOUT));
+        }
+        match self.options.timeout {
+            Some(secs) => Duration::from_secs(secs),
+            None => self.rtt.timeout(self.retries),
+        }
+    }
 }
 
 pub struct TftpServer {
@@ -97,46 +493,204 @@ pub struct TftpServer {
     poll: Poll,
     /// The main timer that can be used to set multiple timeout events.
     timer: Timer<Token>,
-    /// The main server socket that receives RRQ and WRQ packets
-    /// and creates a new separate UDP connection.
-    socket: UdpSocket,
+    /// The server sockets that receive RRQ and WRQ packets and create new
+    /// separate UDP connections, one for every address the server listens on.
+    servers: BTreeMap<Token, UdpSocket>,
     /// The separate UDP connections for handling multiple requests.
     connections: HashMap<Token, ConnectionState>,
+    /// The tokens of the connections served from a server socket
+    /// in single-port mode by their client's address.
+    clients: HashMap<SocketAddr, Token>,
+    /// The ports the sockets of new connections are bound to.
+    ports: PortPool,
+    /// Where requested files are read from and uploads are written to.
+    storage: Box<dyn Storage>,
+    /// The handlers that generate requested files, asked in the order they were added.
+    read_handlers: Vec<ReadHandler>,
+    /// The handlers that take uploads, asked in the order they were added.
+    write_handlers: Vec<WriteHandler>,
+    /// The settings the server was built with.
+    config: ServerConfig,
 }
 
-impl TftpServer {
-    /// Creates a new TFTP server from a random open UDP port.
-    pub fn new() -> Result<TftpServer> {
+/// Builds a `TftpServer` with non-default settings.
+#[derive(Default)]
+pub struct TftpServerBuilder {
+    addrs: Vec<SocketAddr>,
+    root_dir: Option<PathBuf>,
+    storage: Option<Box<dyn Storage>>,
+    read_handlers: Vec<ReadHandler>,
+    write_handlers: Vec<WriteHandler>,
+    port_range: Option<RangeInclusive<u16>>,
+    port_allocation: PortAllocation,
+    config: ServerConfig,
+}
+
+impl TftpServerBuilder {
+    /// Creates a builder with the default settings.
+    pub fn new() -> TftpServerBuilder {
+        TftpServerBuilder::default()
+    }
+
+    /// Adds an address the server listens on. It can be called more than once
+    /// to listen on several addresses, like `0.0.0.0:69` and `[::]:69`.
+    /// If not set, the server picks a random open UDP port on the loopback interface.
+    pub fn addr(mut self, addr: SocketAddr) -> TftpServerBuilder {
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Sets the largest file size in bytes that a WRQ may announce with the tsize option.
+    /// Larger uploads are refused with a disk full error.
+    pub fn max_upload_size(mut self, size: u64) -> TftpServerBuilder {
+        self.config.max_upload_size = Some(size);
+        self
+    }
+
+    /// Sets the kinds of requests the server accepts. Refused requests
+    /// are answered with an access violation error.
+    pub fn access_mode(mut self, access_mode: AccessMode) -> TftpServerBuilder {
+        self.config.access_mode = access_mode;
+        self
+    }
+
+    /// Sets what happens when a WRQ asks for a file that already exists.
+    /// By default such requests are refused.
+    pub fn write_policy(mut self, write_policy: WritePolicy) -> TftpServerBuilder {
+        self.config.write_policy = write_policy;
+        self
+    }
+
+    /// Sets the block number that follows block 65535 for clients
+    /// that do not choose one with the rollover option.
+    pub fn block_rollover(mut self, rollover: BlockRollover) -> TftpServerBuilder {
+        self.config.rollover = rollover;
+        self
+    }
+
+    /// Sets the number of times unacknowledged packets are resent to a silent
+    /// client before its transfer is aborted. Defaults to 5.
+    pub fn max_retries(mut self, max_retries: u32) -> TftpServerBuilder {
+        self.config.max_retries = max_retries;
+        self
+    }
+
+    /// Restricts the ports of the sockets created for transfers to the given
+    /// range, like `50000..=50099`. New transfers are refused while every
+    /// port of the range is in use. If not set, any open port is used.
+    pub fn port_range(mut self, ports: RangeInclusive<u16>) -> TftpServerBuilder {
+        self.port_range = Some(ports);
+        self
+    }
+
+    /// Sets how ports are picked from the port range. Defaults to random.
+    pub fn port_allocation(mut self, allocation: PortAllocation) -> TftpServerBuilder {
+        self.port_allocation = allocation;
+        self
+    }
+
+    /// Serves every transfer from the server socket that received its request,
+    /// telling transfers apart by the client's address, so that only the server
+    /// ports need to be reachable through NAT or a firewall.
+    pub fn single_port(mut self, single_port: bool) -> TftpServerBuilder {
+        self.config.single_port = single_port;
+        self
+    }
+
+    /// Sets the directory that files are served from and uploaded to.
+    /// If not set, files are resolved under the current working directory.
+    pub fn root_dir<P: Into<PathBuf>>(mut self, dir: P) -> TftpServerBuilder {
+        self.root_dir = Some(dir.into());
+        self
+    }
+
+    /// Serves files from and uploads files to the given storage
+    /// instead of a directory of the local file system.
+    pub fn storage<S: Storage + 'static>(mut self, storage: S) -> TftpServerBuilder {
+        self.storage = Some(Box::new(storage));
+        self
+    }
+
+    /// Adds a handler that generates the contents of requested files, for
+    /// example a boot configuration rendered for the requesting host.
+    /// Handlers are asked before the storage, in the order they were added.
+    pub fn read_handler<F>(mut self, handler: F) -> TftpServerBuilder
+        where F: Fn(&str, &SocketAddr) -> Option<io::Result<Vec<u8>>> + Send + 'static
+    {
+        self.read_handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Adds a handler that takes uploads instead of the storage, for example
+    /// to store them in a database. The write policy and the free space of the
+    /// storage do not apply to uploads taken by a handler.
+    /// Handlers are asked before the storage, in the order they were added.
+    pub fn write_handler<F>(mut self, handler: F) -> TftpServerBuilder
+        where F: Fn(&str, Mode, &SocketAddr) -> Option<io::Result<Box<dyn StorageWriter>>>
+                     + Send + 'static
+    {
+        self.write_handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Creates the server with the builder's settings.
+    pub fn build(self) -> Result<TftpServer> {
+        let storage = match self.storage {
+            Some(storage) => storage,
+            None => {
+                let root_dir = self.root_dir.unwrap_or_else(|| PathBuf::from("."));
+                Box::new(LocalStorage::new(root_dir)?)
+            }
+        };
+
+        // IPv6 sockets only accept IPv6 packets if the server also listens
+        // on IPv4, so that both can be bound to the same port.
+        let only_v6 = self.addrs.iter().any(|addr| addr.is_ipv4());
+        let sockets = if self.addrs.is_empty() {
+            vec![create_socket(Some(Duration::from_secs(TIMEOUT)))?]
+        } else {
+            self.addrs
+                .iter()
+                .map(|addr| bind_server_socket(addr, only_v6))
+                .collect::<io::Result<Vec<_>>>()?
+        };
+
         let poll = Poll::new()?;
-        let socket = UdpSocket::from_socket(create_socket(Some(Duration::from_secs(TIMEOUT)))?)?;
         let timer = Timer::default();
-        poll.register(&socket, SERVER, Ready::all(), PollOpt::edge())?;
         poll.register(&timer, TIMER, Ready::readable(), PollOpt::edge())?;
+        let mut servers = BTreeMap::new();
+        for (i, socket) in sockets.into_iter().enumerate() {
+            let socket = UdpSocket::from_socket(socket)?;
+            let token = Token(i + 1);
+            poll.register(&socket, token, Ready::all(), PollOpt::edge())?;
+            servers.insert(token, socket);
+        }
 
         Ok(TftpServer {
-            new_token: 2,
-            poll: poll,
-            timer: timer,
-            socket: socket,
+            new_token: servers.len() + 1,
+            poll,
+            timer,
+            servers,
             connections: HashMap::new(),
+            clients: HashMap::new(),
+            ports: PortPool::new(self.port_range, self.port_allocation),
+            storage,
+            read_handlers: self.read_handlers,
+            write_handlers: self.write_handlers,
+            config: self.config,
         })
     }
+}
+
+impl TftpServer {
+    /// Creates a new TFTP server from a random open UDP port.
+    pub fn new() -> Result<TftpServer> {
+        TftpServerBuilder::new().build()
+    }
 
     /// Creates a new TFTP server from a socket address.
     pub fn new_from_addr(addr: &SocketAddr) -> Result<TftpServer> {
-        let poll = Poll::new()?;
-        let socket = UdpSocket::bind(addr)?;
-        let timer = Timer::default();
-        poll.register(&socket, SERVER, Ready::all(), PollOpt::edge())?;
-        poll.register(&timer, TIMER, Ready::readable(), PollOpt::edge())?;
-
-        Ok(TftpServer {
-            new_token: 2,
-            poll: poll,
-            timer: timer,
-            socket: socket,
-            connections: HashMap::new(),
-        })
+        TftpServerBuilder::new().addr(*addr).build()
     }
 
     /// Returns a new token created from incrementing a counter.
@@ -146,13 +700,26 @@ impl TftpServer {
         token
     }
 
-    /// Cancels a connection given the connection's token. It cancels the
-    /// connection's timeout and deregisters the connection's socket from the event loop.
-    fn cancel_connection(&mut self, token: &Token) -> Result<()> {
+    /// Removes a connection given the connection's token. It cancels the
+    /// connection's timeout and deregisters the connection's socket from the event
+    /// loop, or stops routing its client's packets to it if the socket is shared.
+    fn remove_connection(&mut self, token: &Token) -> Result<Option<ConnectionState>> {
         if let Some(conn) = self.connections.remove(token) {
-            self.poll.deregister(&conn.conn)?;
-            self.timer.cancel_timeout(&conn.timeout).expect("Error canceling timeout");
+            self.timer.cancel_timeout(&conn.timeout);
+            if conn.shared {
+                self.clients.remove(&conn.addr);
+            } else {
+                self.ports.release(conn.conn.local_addr()?.port());
+                self.poll.deregister(&conn.conn)?;
+            }
+            return Ok(Some(conn));
         }
+        Ok(None)
+    }
+
+    /// Cancels a connection given the connection's token.
+    fn cancel_connection(&mut self, token: &Token) -> Result<()> {
+        self.remove_connection(token)?;
         Ok(())
     }
 
@@ -160,53 +727,139 @@ impl TftpServer {
     fn reset_timeout(&mut self, token: &Token) -> Result<()> {
         if let Some(ref mut conn) = self.connections.get_mut(token) {
             self.timer.cancel_timeout(&conn.timeout);
-            conn.timeout = self.timer.set_timeout(Duration::from_secs(TIMEOUT), *token)?;
+            conn.timeout = self.timer.set_timeout(conn.retransmit_timeout(), *token);
         }
         Ok(())
     }
 
-    /// Handles a packet sent to the main server connection.
-    /// It opens a new UDP connection in a random port and replies with either an ACK
-    /// or a DATA packet depending on the whether it received an RRQ or a WRQ packet.
-    fn handle_server_packet(&mut self) -> Result<()> {
-        let mut buf = [0; MAX_PACKET_SIZE];
-        let (amt, src) = match self.socket.recv_from(&mut buf)? {
-            Some((amt, src)) => (amt, src),
+    /// Handles a packet sent to a server socket given the socket's token.
+    /// It opens a new UDP connection in a random port on the same address as the
+    /// server socket and replies with either an ACK or a DATA packet depending on
+    /// the whether it received an RRQ or a WRQ packet, or with an OACK if the
+    /// request contained options that the server accepted.
+    /// In single-port mode the connection shares the server socket instead, and
+    /// packets from clients with an open connection are passed on to it.
+    fn handle_server_packet(&mut self, server_token: Token) -> Result<()> {
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        let (amt, src, local_ip) = match self.servers.get(&server_token) {
+            Some(socket) => {
+                let (amt, src) = recv_from(socket, &mut buf)?;
+                (amt, src, socket.local_addr()?.ip())
+            }
             None => return Err(TftpError::NoneFromSocket),
         };
-        let packet = Packet::read(PacketData::new(buf, amt))?;
+        if let Some(&token) = self.clients.get(&src) {
+            // A client that completed an upload may start its next transfer
+            // while the server still waits for a resent last block.
+            let complete = self.connections.get(&token).is_some_and(|conn| conn.complete);
+            let request = matches!(Packet::read(PacketData::new(&buf, amt)),
+                                   Ok(Packet::RRQ { .. }) | Ok(Packet::WRQ { .. }));
+            if !(complete && request) {
+                let result = self.process_connection_packet(token, &buf[..amt], src);
+                self.handle_connection_result(token, result)?;
+                return Ok(());
+            }
+            info!("Completed transfer with token {:?}", token);
+            self.cancel_connection(&token)?;
+        }
+
+        let packet = match Packet::read(PacketData::new(&buf, amt)) {
+            Err(PacketErr::InvalidMode) => {
+                return Err(TftpError::TftpError(ErrorCode::IllegalTFTP, src))
+            }
+            packet => packet?,
+        };
 
         // Handle the RRQ or WRQ packet.
-        let (file, block_num, send_packet) = match packet {
-            Packet::RRQ { filename, mode } => handle_rrq_packet(filename, mode, &src)?,
-            Packet::WRQ { filename, mode } => handle_wrq_packet(filename, mode, &src)?,
+        let (file, send_packet, options) = match packet {
+            // Mail mode is obsolete (RFC 1350) and is refused.
+            Packet::RRQ { mode: Mode::Mail, .. } | Packet::WRQ { mode: Mode::Mail, .. } => {
+                return Err(TftpError::TftpError(ErrorCode::IllegalTFTP, src))
+            }
+            Packet::RRQ { filename, mode, options } => {
+                handle_rrq_packet(filename,
+                                  mode,
+                                  options,
+                                  &self.config,
+                                  &*self.storage,
+                                  &self.read_handlers,
+                                  &src)?
+            }
+            Packet::WRQ { filename, mode, options } => {
+                handle_wrq_packet(filename,
+                                  mode,
+                                  options,
+                                  &self.config,
+                                  &*self.storage,
+                                  &self.write_handlers,
+                                  &src)?
+            }
             _ => return Err(TftpError::TftpError(ErrorCode::IllegalTFTP, src)),
         };
 
         // Create new connection.
-        let socket = UdpSocket::from_socket(create_socket(Some(Duration::from_secs(TIMEOUT)))?)?;
+        let shared = self.config.single_port;
         let token = self.generate_token();
-        let timeout = self.timer.set_timeout(Duration::from_secs(TIMEOUT), token)?;
-        self.poll.register(&socket, token, Ready::all(), PollOpt::edge())?;
+        let socket = if shared {
+            self.servers[&server_token].try_clone()?
+        } else {
+            let (socket, port) = match self.ports.bind(local_ip) {
+                Err(TftpError::PortRangeExhausted) => {
+                    warn!("Refusing request from {}, no free port in the port range", src);
+                    return Err(TftpError::TftpError(ErrorCode::NotDefined, src));
+                }
+                bound => bound?,
+            };
+            let registered = UdpSocket::from_socket(socket).and_then(|socket| {
+                self.poll.register(&socket, token, Ready::all(), PollOpt::edge())?;
+                Ok(socket)
+            });
+            match registered {
+                Ok(socket) => socket,
+                Err(err) => {
+                    self.ports.release(port);
+                    return Err(err.into());
+                }
+            }
+        };
+        let initial_timeout = Duration::from_secs(options.timeout.unwrap_or(TIMEOUT));
+        let timeout = self.timer.set_timeout(initial_timeout, token);
+        if shared {
+            self.clients.insert(src, token);
+        }
         info!("Created connection with token: {:?}", token);
 
-        socket.send_to(send_packet.clone().bytes()?.to_slice(), &src)?;
-        self.connections.insert(token,
-                                ConnectionState {
-                                    conn: socket,
-                                    file: file,
-                                    timeout: timeout,
-                                    block_num: block_num,
-                                    last_packet: send_packet,
-                                    addr: src,
-                                });
+        let mut conn = ConnectionState {
+            conn: socket,
+            file,
+            timeout,
+            block_num: 0,
+            window: VecDeque::new(),
+            unacked: 0,
+            eof: false,
+            complete: false,
+            retries: 0,
+            rtt: RttEstimator::new(),
+            rtt_probe: None,
+            addr: src,
+            shared,
+            options,
+        };
+        // The connection is registered before its first packets are sent, so
+        // that it is closed like any other connection if sending them fails.
+        let result = send_first_packets(&mut conn, send_packet);
+        self.connections.insert(token, conn);
+        self.handle_connection_result(token, result)?;
 
         Ok(())
     }
 
     /// Handles the event when a timer times out.
     /// It gets the connection from the token and resends
-    /// the last packet sent from the connection.
+    /// the unacknowledged packets of the connection, or aborts the
+    /// transfer if the client stayed silent for too many timeouts.
+    /// A completed WRQ is closed once the client stopped resending its last block,
+    /// and a connection that fails to send is closed without affecting the others.
     fn handle_timer(&mut self) -> Result<()> {
         let mut tokens = Vec::new();
         while let Some(token) = self.timer.poll() {
@@ -214,11 +867,42 @@ impl TftpServer {
         }
 
         for token in tokens {
-            if let Some(ref mut conn) = self.connections.get_mut(&token) {
-                info!("Timeout: resending last packet for token: {:?}", token);
-                conn.conn.send_to(conn.last_packet.clone().bytes()?.to_slice(), &conn.addr)?;
+            let closed = match self.connections.get_mut(&token) {
+                Some(ref mut conn) if conn.complete => {
+                    info!("Completed transfer with token {:?}", token);
+                    true
+                }
+                Some(ref mut conn) if conn.retries >= self.config.max_retries => {
+                    warn!("Giving up on token {:?} after {} retries", token, conn.retries);
+                    let packet = Packet::ERROR {
+                        code: ErrorCode::NotDefined,
+                        msg: "Transfer timed out".to_string(),
+                    };
+                    // The transfer is closed even if the client cannot be told.
+                    if let Err(err) = send_to_client(conn, &packet) {
+                        error!("Error: {:?}", err);
+                    }
+                    true
+                }
+                Some(ref mut conn) => {
+                    info!("Timeout: resending last packets for token: {:?}", token);
+                    conn.retries += 1;
+                    match resend_window(conn) {
+                        Ok(_) => false,
+                        Err(err) => {
+                            error!("Error: {:?}", err);
+                            true
+                        }
+                    }
+                }
+                None => continue,
+            };
+
+            if closed {
+                self.cancel_connection(&token)?;
+            } else {
+                self.reset_timeout(&token)?;
             }
-            self.reset_timeout(&token)?;
         }
 
         Ok(())
@@ -226,13 +910,28 @@ impl TftpServer {
 
     /// Handles a packet sent to an open child connection.
     fn handle_connection_packet(&mut self, token: Token) -> Result<()> {
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        let (amt, src) = match self.connections.get(&token) {
+            Some(conn) => recv_from(&conn.conn, &mut buf)?,
+            None => return Err(TftpError::NoneFromSocket),
+        };
+        self.process_connection_packet(token, &buf[..amt], src)
+    }
+
+    /// Handles a packet from the given address for an open connection
+    /// given the connection's token.
+    fn process_connection_packet(&mut self,
+                                 token: Token,
+                                 buf: &[u8],
+                                 src: SocketAddr)
+                                 -> Result<()> {
         if let Some(ref mut conn) = self.connections.get_mut(&token) {
-            let mut buf = [0; MAX_PACKET_SIZE];
-            let amt = match conn.conn.recv_from(&mut buf)? {
-                Some((amt, _)) => amt,
-                None => return Err(TftpError::NoneFromSocket),
-            };
-            let packet = Packet::read(PacketData::new(buf, amt))?;
+            if src != conn.addr {
+                warn!("Received packet from unknown transfer ID {}", src);
+                return Err(TftpError::UnknownTid(src));
+            }
+            let packet = Packet::read(PacketData::new(buf, buf.len()))?;
+            conn.retries = 0;
 
             match packet {
                 Packet::ACK(block_num) => handle_ack_packet(block_num, conn)?,
@@ -241,8 +940,13 @@ impl TftpServer {
                 }
                 Packet::ERROR { code, msg } => {
                     error!("Error message received with code {:?}: {:?}", code, msg);
-                    return Err(TftpError::TftpError(code, conn.addr));
+                    return Err(TftpError::AbortedByClient);
                 }
+                // In single-port mode a client resending its request reaches the
+                // connection, which resends its first packets if they are not
+                // answered yet. Later copies of the request are ignored.
+                Packet::RRQ { .. } if conn.shared => handle_repeated_request(false, conn)?,
+                Packet::WRQ { .. } if conn.shared => handle_repeated_request(true, conn)?,
                 _ => {
                     error!("Received invalid packet from connection");
                     return Err(TftpError::TftpError(ErrorCode::IllegalTFTP, conn.addr));
@@ -253,51 +957,85 @@ impl TftpServer {
         Ok(())
     }
 
-    /// Handles sending error packets given the error code.
+    /// Handles sending error packets given the error code. An error packet that
+    /// cannot be sent is only logged, since it is not resent anyway.
     fn handle_error(&mut self, token: &Token, code: ErrorCode, addr: &SocketAddr) -> Result<()> {
-        if *token == SERVER {
-            self.socket.send_to(code.to_packet().bytes()?.to_slice(), addr)?;
-        } else if let Some(ref mut conn) = self.connections.get_mut(&token) {
-            conn.conn.send_to(code.to_packet().bytes()?.to_slice(), addr)?;
+        let bytes = code.to_packet().bytes()?;
+        let sent = if let Some(socket) = self.servers.get(token) {
+            socket.send_to(bytes.to_slice(), addr)
+        } else if let Some(conn) = self.connections.get(token) {
+            conn.conn.send_to(bytes.to_slice(), addr)
+        } else {
+            return Ok(());
+        };
+        if let Err(err) = sent {
+            error!("Error sending error packet to {}: {}", addr, err);
         }
         Ok(())
     }
 
+    /// Handles the result of a packet sent to a connection given the connection's
+    /// token, closing the connection if the transfer completed or failed.
+    /// Returns whether the connection is still open.
+    fn handle_connection_result(&mut self, token: Token, result: Result<()>) -> Result<bool> {
+        match result {
+            Ok(()) => {
+                self.reset_timeout(&token)?;
+                return Ok(true);
+            }
+            Err(TftpError::UnknownTid(addr)) => {
+                self.handle_error(&token, ErrorCode::UnknownID, &addr)?;
+                return Ok(true);
+            }
+            Err(TftpError::CloseConnection) => {
+                info!("Completed transfer with token {:?}", token);
+                self.cancel_connection(&token)?;
+                return Ok(false);
+            }
+            Err(TftpError::TftpError(code, addr)) => self.handle_error(&token, code, &addr)?,
+            Err(TftpError::AbortedByClient) => {}
+            Err(e) => error!("Error: {:?}", e),
+        }
+
+        info!("Closing connection with token {:?}", token);
+        self.cancel_connection(&token)?;
+        Ok(false)
+    }
+
     /// Called for every event sent from the event loop. The event
     /// is a token that can either be from the server, from an open connection,
     /// or from a timeout timer for a connection.
     pub fn handle_token(&mut self, token: Token) -> Result<()> {
         match token {
-            SERVER => {
-                match self.handle_server_packet() {
-                    Err(TftpError::NoneFromSocket) => {}
-                    Err(TftpError::TftpError(code, addr)) => {
-                        self.handle_error(&token, code, &addr)?
+            // Sockets are registered as edge triggered, so they
+            // are read until there are no more packets.
+            token if self.servers.contains_key(&token) => {
+                loop {
+                    match self.handle_server_packet(token) {
+                        Err(TftpError::NoneFromSocket) => break,
+                        Err(TftpError::TftpError(code, addr)) => {
+                            self.handle_error(&token, code, &addr)?
+                        }
+                        Err(e) => error!("Error: {:?}", e),
+                        _ => {}
                     }
-                    Err(e) => error!("Error: {:?}", e),
-                    _ => {}
                 }
             }
             TIMER => self.handle_timer()?,
-            token if self.connections.get(&token).is_some() => {
-                match self.handle_connection_packet(token) {
-                    Err(TftpError::CloseConnection) => {}
-                    Err(TftpError::NoneFromSocket) => return Ok(()),
-                    Err(TftpError::TftpError(code, addr)) => {
-                        self.handle_error(&token, code, &addr)?
-                    }
-                    Err(e) => error!("Error: {:?}", e),
-                    _ => {
-                        self.reset_timeout(&token)?;
-                        return Ok(());
+            token if self.connections.contains_key(&token) => {
+                loop {
+                    match self.handle_connection_packet(token) {
+                        Err(TftpError::NoneFromSocket) => break,
+                        result => {
+                            if !self.handle_connection_result(token, result)? {
+                                break;
+                            }
+                        }
                     }
                 }
-
-                info!("Closing connection with token {:?}", token);
-                self.cancel_connection(&token)?;
-                return Ok(());
             }
-            _ => unreachable!(),
+            // The connection was closed while handling an earlier event of the same poll.
+            _ => {}
         }
 
         Ok(())
@@ -315,28 +1053,69 @@ impl TftpServer {
         }
     }
 
-    /// Returns the socket address of the server socket.
+    /// Returns the socket address of the first server socket.
     pub fn local_addr(&self) -> Result<SocketAddr> {
-        Ok(self.socket.local_addr()?)
+        match self.servers.values().next() {
+            Some(socket) => Ok(socket.local_addr()?),
+            None => Err(TftpError::NoOpenSocket),
+        }
     }
+
+    /// Returns the socket addresses of all server sockets
+    /// in the order they were added to the builder.
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        let mut addrs = Vec::new();
+        for socket in self.servers.values() {
+            addrs.push(socket.local_addr()?);
+        }
+        Ok(addrs)
+    }
+}
+
+/// Receives a datagram from a non-blocking socket, returning
+/// `NoneFromSocket` if there is nothing to be read.
+fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+    match socket.recv_from(buf) {
+        Ok(result) => Ok(result),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Err(TftpError::NoneFromSocket),
+        Err(e) => Err(TftpError::IoError(e)),
+    }
+}
+
+/// Binds a server socket to the given address. IPv6 sockets are
+/// restricted to IPv6 packets if `only_v6` is set.
+fn bind_server_socket(addr: &SocketAddr, only_v6: bool) -> io::Result<net::UdpSocket> {
+    match *addr {
+        SocketAddr::V4(_) => net::UdpSocket::bind(addr),
+        SocketAddr::V6(_) => {
+            let builder = UdpBuilder::new_v6()?;
+            if only_v6 {
+                builder.only_v6(true)?;
+            }
+            builder.bind(addr)
+        }
+    }
+}
+
+/// Creates a std::net::UdpSocket on a random open UDP port on the loopback interface.
+pub fn create_socket(timeout: Option<Duration>) -> Result<net::UdpSocket> {
+    create_socket_at(IpAddr::V4(Ipv4Addr::LOCALHOST), timeout)
 }
 
-/// Creates a std::net::UdpSocket on a random open UDP port.
+/// Creates a std::net::UdpSocket on a random open UDP port on the given address.
 /// The range of valid ports is from 0 to 65535 and if the function
 /// cannot find a open port within 100 different random ports it returns an error.
-pub fn create_socket(timeout: Option<Duration>) -> Result<net::UdpSocket> {
+pub fn create_socket_at(ip: IpAddr, timeout: Option<Duration>) -> Result<net::UdpSocket> {
     let mut num_failures = 0;
-    let mut past_ports = HashMap::new();
+    let mut past_ports = HashSet::new();
     loop {
         let port = rand::thread_rng().gen_range(0, 65535);
         // Ignore ports that already failed.
-        if past_ports.get(&port).is_some() {
+        if past_ports.contains(&port) {
             continue;
         }
 
-        let addr = format!("127.0.0.1:{}", port);
-        let socket_addr = SocketAddr::from_str(addr.as_str()).expect("Error parsing address");
-        match net::UdpSocket::bind(&socket_addr) {
+        match net::UdpSocket::bind(SocketAddr::new(ip, port)) {
             Ok(socket) => {
                 if let Some(timeout) = timeout {
                     socket.set_read_timeout(Some(timeout))?;
@@ -345,7 +1124,7 @@ pub fn create_socket(timeout: Option<Duration>) -> Result<net::UdpSocket> {
                 return Ok(socket);
             }
             Err(_) => {
-                past_ports.insert(port, true);
+                past_ports.insert(port);
                 num_failures += 1;
                 if num_failures > 100 {
                     return Err(TftpError::NoOpenSocket);
@@ -355,86 +1134,451 @@ pub fn create_socket(timeout: Option<Duration>) -> Result<net::UdpSocket> {
     }
 }
 
-/// Increments the block number and handles wraparound to 0 instead of overflow.
+/// Increments the block number, rolling over from 65535 to 0.
 pub fn incr_block_num(block_num: &mut u16) {
-    if *block_num == u16::MAX - 1 {
-        *block_num = 0;
+    BlockRollover::Zero.incr(block_num);
+}
+
+/// Reads from the file until the buffer is full or the end of the file
+/// is reached and returns the number of bytes read.
+fn read_block<R: Read>(file: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut amount = 0;
+    while amount < buf.len() {
+        match file.read(&mut buf[amount..]) {
+            Ok(0) => break,
+            Ok(n) => amount += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(TftpError::IoError(e)),
+        }
+    }
+    Ok(amount)
+}
+
+/// Reads the next block of the file into a DATA packet.
+fn read_data_packet<R: Read>(file: &mut R,
+                             block_num: u16,
+                             options: &TransferOptions)
+                             -> Result<Packet> {
+    let mut buf = vec![0; options.blksize];
+    let amount = read_block(file, &mut buf)?;
+    buf.truncate(amount);
+
+    Ok(Packet::DATA {
+        block_num,
+        data: DataBytes(buf),
+        len: amount,
+    })
+}
+
+/// Reads and sends DATA packets for a RRQ until the window
+/// is full or the last block of the file has been sent.
+fn send_window(conn: &mut ConnectionState) -> Result<()> {
+    while conn.window.len() < conn.options.windowsize as usize && !conn.eof {
+        let mut block_num = match conn.window.back() {
+            Some(&Packet::DATA { block_num, .. }) => block_num,
+            _ => conn.block_num,
+        };
+        conn.options.rollover.incr(&mut block_num);
+
+        let addr = conn.addr;
+        let packet = match conn.file {
+            TransferFile::Reader(ref mut file) => {
+                read_data_packet(file, block_num, &conn.options)
+                    .map_err(|err| read_error(err, &addr))?
+            }
+            TransferFile::Writer(_) => {
+                return Err(TftpError::TftpError(ErrorCode::IllegalTFTP, conn.addr))
+            }
+        };
+        if let Packet::DATA { len, .. } = packet {
+            conn.eof = len < conn.options.blksize;
+        }
+        let sent = send_to_client(conn, &packet)?;
+        conn.window.push_back(packet);
+        // The client acknowledges the last block of the window.
+        conn.start_rtt_probe(block_num);
+        if !sent {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Resends the packets of the window that have not been acknowledged.
+/// Returns whether all of them were sent.
+fn resend_window(conn: &mut ConnectionState) -> Result<bool> {
+    conn.rtt_probe = None;
+    for packet in &conn.window {
+        if !send_to_client(conn, packet)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Sends a packet to the client of a connection. Returns `false` if the
+/// socket's send buffer is full, in which case the packet is left to be
+/// resent when the connection's timeout expires.
+fn send_to_client(conn: &ConnectionState, packet: &Packet) -> Result<bool> {
+    match conn.conn.send_to(packet.clone().bytes()?.to_slice(), &conn.addr) {
+        Ok(_) => Ok(true),
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+            warn!("Send buffer full, delaying packets to {}", conn.addr);
+            Ok(false)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Converts an error reading the file of a RRQ into the error to reply to the
+/// client with, so that the client is told that the transfer failed.
+fn read_error(err: TftpError, addr: &SocketAddr) -> TftpError {
+    match err {
+        TftpError::IoError(err) => {
+            error!("Error reading file for {}: {}", addr, err);
+            match storage_error(err, addr) {
+                TftpError::IoError(_) => TftpError::TftpError(ErrorCode::NotDefined, *addr),
+                err => err,
+            }
+        }
+        err => err,
+    }
+}
+
+/// Starts a transfer by sending the reply to its RRQ or WRQ, or the first
+/// window of DATA packets if a RRQ is not answered with an OACK.
+fn send_first_packets(conn: &mut ConnectionState, packet: Option<Packet>) -> Result<()> {
+    match packet {
+        Some(packet) => {
+            send_to_client(conn, &packet)?;
+            conn.window.push_back(packet);
+            // A RRQ continues with ACK 0, a WRQ with the first DATA block.
+            let reply = if let TransferFile::Writer(_) = conn.file { 1 } else { 0 };
+            conn.start_rtt_probe(reply);
+            Ok(())
+        }
+        None => send_window(conn),
+    }
+}
+
+/// Sends an ACK for the last block received in order for a WRQ
+/// and keeps it to be resent when a timeout happens.
+fn send_ack(conn: &mut ConnectionState) -> Result<()> {
+    let packet = Packet::ACK(conn.block_num);
+    send_to_client(conn, &packet)?;
+    conn.window.clear();
+    conn.window.push_back(packet);
+    conn.unacked = 0;
+
+    let mut next = conn.block_num;
+    conn.options.rollover.incr(&mut next);
+    conn.start_rtt_probe(next);
+    Ok(())
+}
+
+/// Parses the options from a RRQ or WRQ and returns the resulting transfer
+/// options along with the options to acknowledge in an OACK.
+/// For a RRQ `file_size` is the size of the requested file, which replaces
+/// the client's tsize value. `rollover` is used unless the client chooses
+/// another one. Unknown or invalid options are silently dropped as
+/// required by RFC 2347.
+fn negotiate_options(options: Vec<TftpOption>,
+                     file_size: Option<u64>,
+                     rollover: BlockRollover)
+                     -> (TransferOptions, Vec<TftpOption>) {
+    let mut negotiated = TransferOptions {
+        rollover,
+        ..TransferOptions::default()
+    };
+    let mut accepted: Vec<TftpOption> = Vec::new();
+    for option in options {
+        let name = option.name.to_lowercase();
+        if accepted.iter().any(|o| o.name == name) {
+            continue;
+        }
+
+        match name.as_str() {
+            "blksize" => {
+                match option.value.parse::<usize>() {
+                    Ok(size) if size >= MIN_BLOCK_SIZE => {
+                        negotiated.blksize = size.min(MAX_BLOCK_SIZE);
+                        accepted.push(TftpOption::new(name, negotiated.blksize.to_string()));
+                    }
+                    _ => info!("Ignoring invalid blksize {}", option.value),
+                }
+            }
+            "tsize" => {
+                match (option.value.parse::<u64>(), file_size) {
+                    (Ok(_), Some(size)) | (Ok(size), None) => {
+                        negotiated.tsize = Some(size);
+                        accepted.push(TftpOption::new(name, size.to_string()));
+                    }
+                    _ => info!("Ignoring invalid tsize {}", option.value),
+                }
+            }
+            "timeout" => {
+                match option.value.parse::<u64>() {
+                    Ok(secs) if (MIN_TIMEOUT..=MAX_TIMEOUT).contains(&secs) => {
+                        negotiated.timeout = Some(secs);
+                        accepted.push(TftpOption::new(name, secs.to_string()));
+                    }
+                    _ => info!("Ignoring invalid timeout {}", option.value),
+                }
+            }
+            "windowsize" => {
+                match option.value.parse::<u16>() {
+                    Ok(size) if size >= 1 => {
+                        negotiated.windowsize = size.min(MAX_WINDOW_SIZE);
+                        accepted.push(TftpOption::new(name, negotiated.windowsize.to_string()));
+                    }
+                    _ => info!("Ignoring invalid windowsize {}", option.value),
+                }
+            }
+            "rollover" => {
+                let rollover = match option.value.as_str() {
+                    "0" => BlockRollover::Zero,
+                    "1" => BlockRollover::One,
+                    _ => {
+                        info!("Ignoring invalid rollover {}", option.value);
+                        continue;
+                    }
+                };
+                negotiated.rollover = rollover;
+                accepted.push(TftpOption::new(name, option.value));
+            }
+            _ => info!("Ignoring unsupported option {}", option.name),
+        }
+    }
+
+    (negotiated, accepted)
+}
+
+/// Returns whether the character can be used in a requested filename.
+/// Control characters and invisible formatting characters, like zero width
+/// spaces and bidirectional overrides, are rejected since they can be used
+/// to disguise the name of a file.
+fn is_allowed_char(c: char) -> bool {
+    match c {
+        '\u{200b}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2060}'..='\u{2069}' |
+        '\u{feff}' | '\u{fff9}'..='\u{fffb}' => false,
+        c => !c.is_control(),
+    }
+}
+
+/// Checks a filename requested in a RRQ or WRQ against the filename policy
+/// shared by both requests. Absolute paths, `..` components, backslashes
+/// and disallowed characters are rejected.
+fn check_filename(filename: &str) -> bool {
+    !filename.is_empty() && !filename.starts_with('/') && !filename.contains('\\') &&
+    filename.chars().all(is_allowed_char) &&
+    filename.split('/').all(|component| component != "..")
+}
+
+/// Checks a filename requested by a client against the filename policy and
+/// returns it as a path relative to the root of the storage, without `.`
+/// components. The request is refused with an access violation if the
+/// filename is rejected.
+fn storage_path(filename: &str, addr: &SocketAddr) -> Result<String> {
+    let path = normalize_path(filename);
+    if check_filename(filename) && !path.is_empty() {
+        Ok(path)
     } else {
-        *block_num += 1;
+        info!("Refusing invalid filename {:?}", filename);
+        Err(TftpError::TftpError(ErrorCode::AccessViolation, *addr))
+    }
+}
+
+/// Converts an error from the storage into the error to reply to the client with.
+/// Errors without a matching TFTP error code are passed on as they are.
+fn storage_error(err: io::Error, addr: &SocketAddr) -> TftpError {
+    let code = match err.kind() {
+        io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+        io::ErrorKind::PermissionDenied => ErrorCode::AccessViolation,
+        io::ErrorKind::AlreadyExists => ErrorCode::FileExists,
+        io::ErrorKind::StorageFull => ErrorCode::DiskFull,
+        _ => return TftpError::IoError(err),
+    };
+    TftpError::TftpError(code, *addr)
+}
+
+/// Converts an error storing the file of a WRQ into the error to reply to the
+/// client with. Errors without a matching error code are sent as `NotDefined`.
+fn write_error(err: io::Error, addr: &SocketAddr) -> TftpError {
+    match storage_error(err, addr) {
+        TftpError::IoError(err) => {
+            error!("Error writing file for {}: {}", addr, err);
+            TftpError::TftpError(ErrorCode::NotDefined, *addr)
+        }
+        err => err,
     }
 }
 
 fn handle_rrq_packet(filename: String,
-                     mode: String,
+                     mode: Mode,
+                     options: Vec<TftpOption>,
+                     config: &ServerConfig,
+                     storage: &dyn Storage,
+                     read_handlers: &[ReadHandler],
                      addr: &SocketAddr)
-                     -> Result<(File, u16, Packet)> {
+                     -> Result<(TransferFile, Option<Packet>, TransferOptions)> {
     info!("Received RRQ packet with filename {} and mode {}",
              filename,
              mode);
-
-    if filename.contains("..") || filename.starts_with("/") {
-        return Err(TftpError::TftpError(ErrorCode::FileNotFound, *addr));
+    if config.access_mode == AccessMode::WriteOnly {
+        return Err(TftpError::TftpError(ErrorCode::AccessViolation, *addr));
     }
 
-    let mut file = File::open(filename)
-        .map_err(|_| TftpError::TftpError(ErrorCode::FileNotFound, *addr))?;
-    let block_num = 1;
+    // Files that cannot be opened for another reason are reported as not found.
+    let path = storage_path(&filename, addr)?;
+    let not_found = |err| match storage_error(err, addr) {
+        TftpError::IoError(_) => TftpError::TftpError(ErrorCode::FileNotFound, *addr),
+        err => err,
+    };
+    let (file, file_size): (Box<dyn Read + Send>, u64) =
+        match read_handlers.iter().find_map(|handler| handler(&path, addr)) {
+            Some(contents) => {
+                let contents = contents.map_err(not_found)?;
+                let len = contents.len() as u64;
+                (Box::new(io::Cursor::new(contents)), len)
+            }
+            None => {
+                let (file, metadata) = storage.open_with_metadata(&path).map_err(not_found)?;
+                (file, metadata.len)
+            }
+        };
 
-    let mut buf = [0; 512];
-    let amount = file.read(&mut buf)?;
+    // The size of a file sent in netascii is only known once the whole file
+    // has been translated, so the tsize option is not acknowledged for it.
+    let options = if mode == Mode::NetAscii {
+        options.into_iter().filter(|option| !option.name.eq_ignore_ascii_case("tsize")).collect()
+    } else {
+        options
+    };
 
-    // Reply with first data packet with a block number of 1.
-    let last_packet = Packet::DATA {
-        block_num: block_num,
-        data: DataBytes(buf),
-        len: amount,
+    // Reply with an OACK and wait for the client to ACK block 0.
+    let (options, accepted) = negotiate_options(options, Some(file_size), config.rollover);
+    let file = if mode == Mode::NetAscii {
+        TransferFile::Reader(Box::new(NetasciiReader::new(file)))
+    } else {
+        TransferFile::Reader(Box::new(file))
     };
+    if !accepted.is_empty() {
+        return Ok((file, Some(Packet::OACK(accepted)), options));
+    }
 
-    Ok((file, block_num, last_packet))
+    // Reply with the first window of data packets starting with a block number of 1.
+    Ok((file, None, options))
 }
 
 fn handle_wrq_packet(filename: String,
-                     mode: String,
+                     mode: Mode,
+                     options: Vec<TftpOption>,
+                     config: &ServerConfig,
+                     storage: &dyn Storage,
+                     write_handlers: &[WriteHandler],
                      addr: &SocketAddr)
-                     -> Result<(File, u16, Packet)> {
+                     -> Result<(TransferFile, Option<Packet>, TransferOptions)> {
     info!("Received WRQ packet with filename {} and mode {}",
              filename,
              mode);
-    if let Ok(_) = fs::metadata(&filename) {
-        return Err(TftpError::TftpError(ErrorCode::FileExists, *addr));
+    if config.access_mode == AccessMode::ReadOnly {
+        return Err(TftpError::TftpError(ErrorCode::AccessViolation, *addr));
+    }
+    let path = storage_path(&filename, addr)?;
+
+    // Refuse uploads that are announced to be too large before creating the file.
+    let (options, accepted) = negotiate_options(options, None, config.rollover);
+    let exceeds = |limit: Option<u64>| {
+        match (options.tsize, limit) {
+            (Some(tsize), Some(limit)) if tsize > limit => {
+                info!("Refusing WRQ with tsize {}", tsize);
+                Err(TftpError::TftpError(ErrorCode::DiskFull, *addr))
+            }
+            _ => Ok(()),
+        }
+    };
+    exceeds(config.max_upload_size)?;
+
+    let handler = write_handlers.iter().find_map(|handler| handler(&path, mode, addr));
+    let (writer, space) = match handler {
+        Some(writer) => (writer.map_err(|err| write_error(err, addr))?, None),
+        None => {
+            let exists = storage.stat(&path).is_ok();
+            if exists && config.write_policy == WritePolicy::Reject {
+                return Err(TftpError::TftpError(ErrorCode::FileExists, *addr));
+            }
+            let space = storage.available_space(&path);
+            exceeds(space)?;
+            let writer = storage.create(&path, config.write_policy)
+                .map_err(|err| write_error(err, addr))?;
+            (writer, space)
+        }
+    };
+    // The limits are enforced again while the upload is received,
+    // since the client may not announce its size or announce a wrong one.
+    let limit = [config.max_upload_size, space].iter().flatten().min().cloned();
+    let writer = if mode == Mode::NetAscii {
+        UploadWriter::NetAscii(NetasciiWriter::new(writer))
+    } else {
+        UploadWriter::Octet(writer)
+    };
+    let file = TransferFile::Writer(Upload::new(writer, limit));
+
+    // Reply with an OACK in place of ACK 0 if any options were accepted.
+    if !accepted.is_empty() {
+        return Ok((file, Some(Packet::OACK(accepted)), options));
     }
-    let file = File::create(filename)?;
-    let block_num = 0;
 
     // Reply with ACK with a block number of 0.
-    let last_packet = Packet::ACK(block_num);
+    Ok((file, Some(Packet::ACK(0)), options))
+}
 
-    Ok((file, block_num, last_packet))
+/// Handles a request that a client resent to the connection started by it,
+/// where `write` tells whether the request is a WRQ.
+fn handle_repeated_request(write: bool, conn: &mut ConnectionState) -> Result<()> {
+    let writer = matches!(conn.file, TransferFile::Writer(_));
+    if write != writer {
+        error!("Received a request for another transfer from {}", conn.addr);
+        return Err(TftpError::TftpError(ErrorCode::IllegalTFTP, conn.addr));
+    }
+    info!("Received repeated request from {}", conn.addr);
+    if conn.block_num == 0 && !conn.complete {
+        resend_window(conn)?;
+    }
+    Ok(())
 }
 
 fn handle_ack_packet(block_num: u16, conn: &mut ConnectionState) -> Result<()> {
     info!("Received ACK with block number {}", block_num);
-    if block_num != conn.block_num {
-        return Ok(());
-    }
-
-    incr_block_num(&mut conn.block_num);
-    let mut buf = [0; 512];
-    let amount = conn.file.read(&mut buf)?;
 
-    // Send next data packet.
-    conn.last_packet = Packet::DATA {
-        block_num: conn.block_num,
-        data: DataBytes(buf),
-        len: amount,
+    // Find the acknowledged packet in the window. An ACK with a block
+    // number of 0 acknowledges the OACK. ACKs for packets outside of the
+    // window are duplicates and are ignored, never answered with DATA, to
+    // avoid the Sorcerer's Apprentice Syndrome (RFC 1123, section 4.2.3.1).
+    let acked = conn.window.iter().position(|packet| match *packet {
+        Packet::DATA { block_num: num, .. } => num == block_num,
+        Packet::OACK(_) => block_num == 0,
+        _ => false,
+    });
+    let acked = match acked {
+        Some(index) => index,
+        None => return Ok(()),
     };
-    conn.conn.send_to(conn.last_packet.clone().bytes()?.to_slice(), &conn.addr)?;
+    conn.window.drain(..acked + 1);
+    conn.block_num = block_num;
+    conn.finish_rtt_probe(block_num);
 
-    if amount < 512 {
-        Err(TftpError::CloseConnection)
-    } else {
-        Ok(())
+    if conn.window.is_empty() && conn.eof {
+        return Err(TftpError::CloseConnection);
+    }
+
+    // Start a new window after the acknowledged block, resending
+    // the blocks of the old window that were not acknowledged.
+    if !conn.window.is_empty() && !resend_window(conn)? {
+        return Ok(());
     }
+    send_window(conn)
 }
 
 fn handle_data_packet(block_num: u16,
@@ -444,20 +1588,46 @@ fn handle_data_packet(block_num: u16,
                       -> Result<()> {
     info!("Received data with block number {}", block_num);
 
-    incr_block_num(&mut conn.block_num);
-    if block_num != conn.block_num {
+    // Reply to a duplicate or out of order block with an ACK for
+    // the last block received so that the client resends after it.
+    // Once the last block was received, every block is a duplicate.
+    let mut expected = conn.block_num;
+    conn.options.rollover.incr(&mut expected);
+    if block_num != expected || conn.complete {
+        send_ack(conn)?;
+        conn.rtt_probe = None;
         return Ok(());
     }
+    conn.finish_rtt_probe(block_num);
+
+    match conn.file {
+        TransferFile::Writer(ref mut file) => {
+            file.write_all(&data.0[0..len]).map_err(|err| write_error(err, &conn.addr))?
+        }
+        TransferFile::Reader(_) => {
+            return Err(TftpError::TftpError(ErrorCode::IllegalTFTP, conn.addr))
+        }
+    }
+    conn.block_num = block_num;
+    conn.unacked += 1;
 
-    conn.file.write(&data.0[0..len])?;
+    // The upload is moved into place before the last block is acknowledged,
+    // so that the client is told with an error if that fails.
+    let last_block = len < conn.options.blksize;
+    if last_block {
+        if let TransferFile::Writer(ref mut upload) = conn.file {
+            upload.finish().map_err(|err| write_error(err, &conn.addr))?;
+        }
+    }
 
-    // Send ACK packet for data.
-    conn.last_packet = Packet::ACK(conn.block_num);
-    conn.conn.send_to(conn.last_packet.clone().bytes()?.to_slice(), &conn.addr)?;
+    // The ACK for the last block received is resent on timeout even
+    // if the window is not complete yet.
+    conn.window.clear();
+    conn.window.push_back(Packet::ACK(block_num));
 
-    if len < 512 {
-        Err(TftpError::CloseConnection)
-    } else {
-        Ok(())
+    if last_block || conn.unacked >= conn.options.windowsize {
+        send_ack(conn)?;
     }
+    conn.complete = last_block;
+    Ok(())
 }
@@ -0,0 +1,509 @@
+use rand;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// What happens when a WRQ asks for a file that already exists.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum WritePolicy {
+    /// The request is refused with a file exists error.
+    #[default]
+    Reject,
+    /// The contents of the existing file are overwritten in place when the
+    /// transfer completes, keeping the file's permissions and hard links.
+    /// Unlike the other policies this is not atomic: the upload is checked to
+    /// fit on the file system first, but an I/O error while it is copied
+    /// leaves the file partly overwritten.
+    Overwrite,
+    /// The existing file is atomically replaced when the transfer completes.
+    Replace,
+    /// Like `Replace`, but the existing file is first renamed to `name.1`, an
+    /// existing `name.1` to `name.2` and so on, keeping at most the given number
+    /// of older versions.
+    Versioned(u32),
+}
+
+/// Information about a stored file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Metadata {
+    /// The size of the file in bytes.
+    pub len: u64,
+}
+
+/// Where a `TftpServer` reads requested files from and writes uploads to.
+///
+/// Paths are relative to the root of the storage, are separated by `/`
+/// and have already been checked against the server's filename policy.
+/// Errors are reported to the client according to their kind: `NotFound`
+/// as file not found, `PermissionDenied` as access violation,
+/// `AlreadyExists` as file exists and `StorageFull` as disk full.
+pub trait Storage: Send {
+    /// Opens the file at the given path for a RRQ.
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Returns information about the file at the given path,
+    /// failing with `NotFound` if there is no such file.
+    fn stat(&self, path: &str) -> io::Result<Metadata>;
+
+    /// Opens the file at the given path for a RRQ along with information about
+    /// it, so that a storage can look the file up only once. The default
+    /// implementation calls `open` and then `stat`.
+    fn open_with_metadata(&self, path: &str) -> io::Result<(Box<dyn Read + Send>, Metadata)> {
+        let file = self.open(path)?;
+        Ok((file, self.stat(path)?))
+    }
+
+    /// Starts an upload to the given path for a WRQ. An existing file at the
+    /// path must be kept until the upload is finalized, and replaced then
+    /// according to the write policy.
+    fn create(&self, path: &str, policy: WritePolicy) -> io::Result<Box<dyn StorageWriter>>;
+
+    /// Returns the number of bytes that can still be stored at the given path,
+    /// or `None` if it is not known.
+    fn available_space(&self, _path: &str) -> Option<u64> {
+        None
+    }
+}
+
+/// An upload started with `Storage::create` that the contents of a WRQ are
+/// written to. Exactly one of `finalize` or `abort` is called when the
+/// transfer ends.
+pub trait StorageWriter: Write + Send {
+    /// Completes the upload after the last block has been written,
+    /// making the contents available under the requested path.
+    /// An upload that fails to finalize is not aborted afterwards.
+    fn finalize(&mut self) -> io::Result<()>;
+
+    /// Discards the upload after the transfer failed.
+    fn abort(&mut self);
+}
+
+/// Stores files in a directory of the local file system.
+pub struct LocalStorage {
+    /// The canonical path of the directory that all paths are resolved under.
+    root_dir: PathBuf,
+}
+
+impl LocalStorage {
+    /// Creates a storage for the given directory, which has to exist.
+    pub fn new<P: AsRef<Path>>(root_dir: P) -> io::Result<LocalStorage> {
+        Ok(LocalStorage { root_dir: fs::canonicalize(root_dir)? })
+    }
+
+    /// Resolves a path under the root directory, following symbolic links.
+    /// Paths that end up outside of the root are refused with `PermissionDenied`.
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let access_violation = || {
+            io::Error::new(io::ErrorKind::PermissionDenied,
+                           "path is outside of the root directory")
+        };
+        let not_found = |_| io::Error::new(io::ErrorKind::NotFound, "path does not exist");
+
+        let path = self.root_dir.join(path);
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(access_violation()),
+        };
+
+        // The file itself may not exist yet for a WRQ, so its parent is resolved.
+        let parent = fs::canonicalize(parent).map_err(not_found)?;
+        let mut resolved = parent.join(name);
+        if fs::symlink_metadata(&resolved).is_ok() {
+            resolved = fs::canonicalize(&resolved).map_err(not_found)?;
+        }
+
+        if resolved.starts_with(&self.root_dir) && resolved != self.root_dir {
+            Ok(resolved)
+        } else {
+            info!("Refusing access to {:?} outside of {:?}", resolved, self.root_dir);
+            Err(access_violation())
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read + Send>> {
+        Ok(self.open_with_metadata(path)?.0)
+    }
+
+    fn open_with_metadata(&self, path: &str) -> io::Result<(Box<dyn Read + Send>, Metadata)> {
+        let path = self.resolve(path)?;
+        if is_temp_file(&path) {
+            return Err(temp_file_error(io::ErrorKind::NotFound));
+        }
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(not_regular_file(io::ErrorKind::NotFound));
+        }
+        Ok((Box::new(file), Metadata { len: metadata.len() }))
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        let path = self.resolve(path)?;
+        if is_temp_file(&path) {
+            return Err(temp_file_error(io::ErrorKind::NotFound));
+        }
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() {
+            return Err(not_regular_file(io::ErrorKind::NotFound));
+        }
+        Ok(Metadata { len: metadata.len() })
+    }
+
+    fn create(&self, path: &str, policy: WritePolicy) -> io::Result<Box<dyn StorageWriter>> {
+        let path = self.resolve(path)?;
+        if is_temp_file(&path) {
+            return Err(temp_file_error(io::ErrorKind::PermissionDenied));
+        }
+        if fs::metadata(&path).is_ok_and(|metadata| !metadata.is_file()) {
+            return Err(not_regular_file(io::ErrorKind::PermissionDenied));
+        }
+        let (file, temp_path) = create_temp_file(&path)?;
+        Ok(Box::new(LocalWriter {
+            file: Some(file),
+            temp_path: Some(temp_path),
+            path,
+            policy,
+        }))
+    }
+
+    fn available_space(&self, path: &str) -> Option<u64> {
+        let path = self.resolve(path).ok()?;
+        available_space(path.parent().unwrap_or(&self.root_dir))
+    }
+}
+
+/// An upload to the local file system. The upload is written to a hidden
+/// temporary file in the same directory as the requested file, which is
+/// only moved into place once the upload is finalized.
+/// The temporary file is removed if the upload is aborted or dropped before that.
+struct LocalWriter {
+    /// The temporary file being written to until it is closed.
+    file: Option<File>,
+    /// The path of the temporary file until it is moved into place or removed.
+    temp_path: Option<PathBuf>,
+    /// The resolved path of the requested file.
+    path: PathBuf,
+    /// The policy the upload was started with.
+    policy: WritePolicy,
+}
+
+impl LocalWriter {
+    /// Moves the finished temporary file to the requested path
+    /// according to the write policy.
+    fn move_into_place(&self, temp_path: &Path) -> io::Result<()> {
+        match self.policy {
+            WritePolicy::Reject => {
+                // Unlike a rename, linking fails instead of replacing
+                // a file that was created during the upload.
+                fs::hard_link(temp_path, &self.path).map_err(|err| {
+                    match err.kind() {
+                        io::ErrorKind::AlreadyExists => {
+                            io::Error::new(io::ErrorKind::AlreadyExists,
+                                           "file was created during the upload")
+                        }
+                        _ => err,
+                    }
+                })?;
+                if let Err(err) = fs::remove_file(temp_path) {
+                    warn!("Error removing temporary file {:?}: {}", temp_path, err);
+                }
+                Ok(())
+            }
+            WritePolicy::Overwrite => {
+                let existing = match fs::metadata(&self.path) {
+                    Ok(metadata) => metadata.len(),
+                    Err(_) => return fs::rename(temp_path, &self.path),
+                };
+                // The existing file is truncated before the upload is copied
+                // into it, so the upload has to fit into the space left.
+                let len = fs::metadata(temp_path)?.len();
+                let dir = self.path.parent().unwrap_or(&self.path);
+                if available_space(dir).is_some_and(|space| space.saturating_add(existing) < len) {
+                    return Err(io::Error::new(io::ErrorKind::StorageFull,
+                                              "upload does not fit in place of the file"));
+                }
+                io::copy(&mut File::open(temp_path)?, &mut File::create(&self.path)?)?;
+                fs::remove_file(temp_path)
+            }
+            WritePolicy::Replace => fs::rename(temp_path, &self.path),
+            WritePolicy::Versioned(retain) => {
+                rotate_versions(&self.path, retain)?;
+                fs::rename(temp_path, &self.path)
+            }
+        }
+    }
+}
+
+impl Write for LocalWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.file {
+            Some(ref mut file) => file.write(buf),
+            None => Err(io::Error::other("upload is already closed")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file {
+            Some(ref mut file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StorageWriter for LocalWriter {
+    /// Moves the temporary file into place, keeping older versions of the file
+    /// if the policy asks for it. The temporary file is removed if it cannot be
+    /// moved into place.
+    fn finalize(&mut self) -> io::Result<()> {
+        self.file = None;
+        let temp_path = match self.temp_path.take() {
+            Some(temp_path) => temp_path,
+            None => return Ok(()),
+        };
+
+        let result = self.move_into_place(&temp_path);
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    fn abort(&mut self) {
+        self.file = None;
+        if let Some(temp_path) = self.temp_path.take() {
+            info!("Removing incomplete upload of {:?}", self.path);
+            let _ = fs::remove_file(&temp_path);
+        }
+    }
+}
+
+impl Drop for LocalWriter {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+/// Stores files in memory, for tests and for embedding the server
+/// in programs that do not want to touch the file system.
+///
+/// Clones share the same files, so the host program can keep a clone to add
+/// files and inspect uploads while the server uses another. Uploads become
+/// visible once their last block has arrived.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    /// Creates an empty storage.
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// Adds a file with the given contents, replacing an existing file at the path.
+    pub fn insert<C: Into<Vec<u8>>>(&self, path: &str, contents: C) {
+        self.files().insert(normalize_path(path), contents.into());
+    }
+
+    /// Returns a copy of the contents of the file at the given path.
+    pub fn get(&self, path: &str) -> Option<Vec<u8>> {
+        self.files().get(&normalize_path(path)).cloned()
+    }
+
+    /// Removes the file at the given path and returns its contents.
+    pub fn remove(&self, path: &str) -> Option<Vec<u8>> {
+        self.files().remove(&normalize_path(path))
+    }
+
+    /// Returns the paths of all stored files in sorted order.
+    pub fn paths(&self) -> Vec<String> {
+        let mut paths = self.files().keys().cloned().collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    /// Locks the files, ignoring a panic of another thread holding the lock
+    /// since every change to the map is a single insert or remove.
+    fn files(&self) -> MutexGuard<'_, HashMap<String, Vec<u8>>> {
+        self.files.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read + Send>> {
+        let contents = self.get(path).ok_or_else(memory_not_found)?;
+        Ok(Box::new(Cursor::new(contents)))
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        let len = self.files().get(&normalize_path(path)).ok_or_else(memory_not_found)?.len();
+        Ok(Metadata { len: len as u64 })
+    }
+
+    fn create(&self, path: &str, policy: WritePolicy) -> io::Result<Box<dyn StorageWriter>> {
+        Ok(Box::new(MemoryWriter {
+            storage: self.clone(),
+            path: normalize_path(path),
+            policy,
+            contents: Vec::new(),
+        }))
+    }
+}
+
+/// An upload to a `MemoryStorage`, buffered until it is finalized.
+struct MemoryWriter {
+    storage: MemoryStorage,
+    path: String,
+    policy: WritePolicy,
+    contents: Vec<u8>,
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.contents.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StorageWriter for MemoryWriter {
+    /// Stores the buffered contents under the requested path,
+    /// keeping older versions of the file if the policy asks for it.
+    fn finalize(&mut self) -> io::Result<()> {
+        let mut files = self.storage.files();
+        let exists = files.contains_key(&self.path);
+        match self.policy {
+            WritePolicy::Reject if exists => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                          "file was created during the upload"));
+            }
+            WritePolicy::Versioned(retain) if exists && retain > 0 => {
+                for version in (1..retain).rev() {
+                    let older = format!("{}.{}", self.path, version);
+                    if let Some(contents) = files.remove(&older) {
+                        files.insert(format!("{}.{}", self.path, version + 1), contents);
+                    }
+                }
+                if let Some(contents) = files.remove(&self.path) {
+                    files.insert(format!("{}.1", self.path), contents);
+                }
+            }
+            _ => {}
+        }
+        files.insert(self.path.clone(), mem::take(&mut self.contents));
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        self.contents = Vec::new();
+    }
+}
+
+/// Brings a path into the form the server passes to a storage,
+/// without empty or `.` components.
+pub(crate) fn normalize_path(path: &str) -> String {
+    path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn memory_not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "file does not exist")
+}
+
+/// Returns the error for a path of the local file system that is a directory
+/// or another kind of file that cannot be transferred.
+fn not_regular_file(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "path is not a regular file")
+}
+
+/// Returns the number of bytes available to unprivileged users
+/// on the file system containing the given directory.
+#[cfg(unix)]
+fn available_space(dir: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { ::std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// Creates a new hidden temporary file in the same directory as the given path
+/// so that it can be renamed to the path atomically.
+fn create_temp_file(path: &Path) -> io::Result<(File, PathBuf)> {
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    loop {
+        let temp_name = format!(".{}.{:08x}.tmp", name, rand::random::<u32>());
+        let temp_path = path.with_file_name(temp_name);
+        match fs::OpenOptions::new().write(true).create_new(true).open(&temp_path) {
+            Ok(file) => return Ok((file, temp_path)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Returns whether the path names a temporary file of an upload in progress,
+/// which must not be served or written to.
+fn is_temp_file(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    let stem = match name.strip_prefix('.').and_then(|name| name.strip_suffix(".tmp")) {
+        Some(stem) => stem,
+        None => return false,
+    };
+    match stem.len().checked_sub(9).and_then(|dot| stem.get(dot..)) {
+        Some(suffix) => {
+            suffix.starts_with('.') && suffix[1..].bytes().all(|b| b.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
+
+fn temp_file_error(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "path is a temporary upload file")
+}
+
+/// Returns the path of the given version of a file, like `name.1`.
+fn version_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", version));
+    path.with_file_name(name)
+}
+
+/// Shifts the existing versions of a file up by one, moving the file itself
+/// to version 1 and dropping versions beyond the number to retain.
+fn rotate_versions(path: &Path, retain: u32) -> io::Result<()> {
+    if retain == 0 || fs::metadata(path).is_err() {
+        return Ok(());
+    }
+
+    for version in (1..retain).rev() {
+        let older = version_path(path, version);
+        if fs::metadata(&older).is_ok() {
+            fs::rename(&older, version_path(path, version + 1))?;
+        }
+    }
+    fs::rename(path, version_path(path, 1))
+}
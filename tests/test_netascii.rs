@@ -0,0 +1,69 @@
+extern crate tftp_server;
+
+use std::io::{Read, Write};
+use tftp_server::netascii::{NetasciiReader, NetasciiWriter};
+
+/// Reads the whole input through a `NetasciiReader` using reads of the given size.
+fn encode(input: &[u8], read_size: usize) -> Vec<u8> {
+    let mut reader = NetasciiReader::new(input);
+    let mut output = Vec::new();
+    let mut buf = vec![0; read_size];
+    loop {
+        let amount = reader.read(&mut buf).unwrap();
+        if amount == 0 {
+            break;
+        }
+        output.extend_from_slice(&buf[0..amount]);
+    }
+    output
+}
+
+/// Writes the whole input through a `NetasciiWriter` using writes of the given size.
+fn decode(input: &[u8], write_size: usize) -> Vec<u8> {
+    let mut output = Vec::new();
+    {
+        let mut writer = NetasciiWriter::new(&mut output);
+        for chunk in input.chunks(write_size) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+    output
+}
+
+macro_rules! netascii {
+    ($name:ident, $local:expr, $netascii:expr) => {
+        #[test]
+        fn $name() {
+            for size in 1..8 {
+                assert_eq!(encode($local, size), $netascii.to_vec());
+                assert_eq!(decode($netascii, size), $local.to_vec());
+            }
+            assert_eq!(encode($local, 512), $netascii.to_vec());
+            assert_eq!(decode($netascii, 512), $local.to_vec());
+        }
+    };
+}
+
+netascii!(plain_text, b"hello world", b"hello world");
+netascii!(empty, b"", b"");
+netascii!(line_feed, b"hello\nworld\n", b"hello\r\nworld\r\n");
+netascii!(carriage_return, b"hello\rworld", b"hello\r\0world");
+netascii!(consecutive_newlines, b"\n\n\r\r\n", b"\r\n\r\n\r\0\r\0\r\n");
+netascii!(trailing_carriage_return, b"hello\r", b"hello\r\0");
+
+#[test]
+fn lone_carriage_return_is_kept() {
+    assert_eq!(decode(b"a\rb", 1), b"a\rb".to_vec());
+    assert_eq!(decode(b"a\r", 1), b"a\r".to_vec());
+}
+
+#[test]
+fn held_carriage_return_is_discarded_without_finish() {
+    let mut output = Vec::new();
+    {
+        let mut writer = NetasciiWriter::new(&mut output);
+        writer.write_all(b"a\r").unwrap();
+    }
+    assert_eq!(output, b"a".to_vec());
+}
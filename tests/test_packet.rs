@@ -20,22 +20,98 @@ const BYTE_DATA: [u8; 512] = [123; 512];
 packet!(rrq,
         Packet::RRQ {
             filename: "/a/b/c/hello.txt".to_string(),
-            mode: "netascii".to_string(),
+            mode: Mode::NetAscii,
+            options: vec![],
         });
 packet!(wrq,
         Packet::WRQ {
             filename: "./world.txt".to_string(),
-            mode: "octet".to_string(),
+            mode: Mode::Octet,
+            options: vec![],
+        });
+packet!(rrq_options,
+        Packet::RRQ {
+            filename: "pxelinux.0".to_string(),
+            mode: Mode::Octet,
+            options: vec![TftpOption::new("blksize", "1428"), TftpOption::new("tsize", "0")],
+        });
+packet!(wrq_options,
+        Packet::WRQ {
+            filename: "config.bin".to_string(),
+            mode: Mode::Octet,
+            options: vec![TftpOption::new("timeout", "5")],
         });
 packet!(ack, Packet::ACK(1234));
 packet!(data,
         Packet::DATA {
             block_num: 1234,
-            data: DataBytes(BYTE_DATA),
+            data: DataBytes(BYTE_DATA.to_vec()),
             len: 512,
         });
+packet!(data_short,
+        Packet::DATA {
+            block_num: 1,
+            data: DataBytes(vec![1, 2, 3]),
+            len: 3,
+        });
+packet!(data_large_block,
+        Packet::DATA {
+            block_num: 1,
+            data: DataBytes(vec![42; MAX_BLOCK_SIZE]),
+            len: MAX_BLOCK_SIZE,
+        });
 packet!(err,
         Packet::ERROR {
             code: ErrorCode::NoUser,
             msg: "This is a message".to_string(),
         });
+packet!(oack,
+        Packet::OACK(vec![TftpOption::new("blksize", "1428"), TftpOption::new("tsize", "1234")]));
+packet!(oack_empty, Packet::OACK(vec![]));
+
+#[test]
+fn data_too_large() {
+    let packet = Packet::DATA {
+        block_num: 1,
+        data: DataBytes(vec![42; MAX_BLOCK_SIZE + 1]),
+        len: MAX_BLOCK_SIZE + 1,
+    };
+    assert!(packet.bytes().is_err());
+}
+
+#[test]
+fn packet_too_short() {
+    assert!(Packet::read(PacketData::new(&[0, 4, 0], 3)).is_err());
+}
+
+/// Returns the bytes of a RRQ packet with the given raw mode string.
+fn rrq_bytes_with_mode(mode: &str) -> Vec<u8> {
+    let mut bytes = vec![0, 1];
+    bytes.extend_from_slice(b"hello.txt\0");
+    bytes.extend_from_slice(mode.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+#[test]
+fn mode_case_insensitive() {
+    for &(name, mode) in &[("OcTeT", Mode::Octet), ("NETASCII", Mode::NetAscii), ("Mail", Mode::Mail)] {
+        let bytes = rrq_bytes_with_mode(name);
+        let packet = Packet::read(PacketData::new(&bytes, bytes.len())).unwrap();
+        assert_eq!(packet,
+                   Packet::RRQ {
+                       filename: "hello.txt".to_string(),
+                       mode,
+                       options: vec![],
+                   });
+    }
+}
+
+#[test]
+fn mode_unknown() {
+    let bytes = rrq_bytes_with_mode("foo");
+    match Packet::read(PacketData::new(&bytes, bytes.len())) {
+        Err(PacketErr::InvalidMode) => {}
+        result => panic!("Expected invalid mode error, got: {:?}", result),
+    }
+}
@@ -1,102 +1,119 @@
-#![feature(question_mark)]
-
-#[macro_use]
-extern crate log;
-
 extern crate env_logger;
+extern crate flate2;
+extern crate tar;
 extern crate tftp_server;
+extern crate zip;
 
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::{Read, Write};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
-use tftp_server::packet::{ErrorCode, DataBytes, Packet, PacketData, MAX_PACKET_SIZE};
-use tftp_server::server::{create_socket, incr_block_num, Result, TftpServer};
+use std::time::{Duration, Instant, SystemTime};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use tftp_server::archive::ArchiveStorage;
+use tftp_server::packet::{ErrorCode, DataBytes, Mode, Packet, PacketData, TftpOption,
+                          MAX_PACKET_SIZE};
+use tftp_server::server::{create_socket, create_socket_at, incr_block_num, AccessMode,
+                          BlockRollover, PortAllocation, Result, TftpServerBuilder, WritePolicy};
+use tftp_server::storage::{MemoryStorage, Metadata, Storage, StorageWriter};
 
 const TIMEOUT: u64 = 3;
 
 /// Starts the server in a new thread.
 pub fn start_server() -> Result<SocketAddr> {
-    let mut server = TftpServer::new()?;
+    start_configured_server(TftpServerBuilder::new())
+}
+
+/// Starts a server built from the given builder in a new thread.
+pub fn start_configured_server(builder: TftpServerBuilder) -> Result<SocketAddr> {
+    let mut server = builder.build()?;
     let addr = server.local_addr()?;
     thread::spawn(move || {
         if let Err(e) = server.run() {
             println!("Error with server: {:?}", e);
         }
-        ()
     });
 
     Ok(addr)
 }
 
-pub fn check_similar_files(file1: &mut File, file2: &mut File) -> Result<()> {
-    let mut buf1 = String::new();
-    let mut buf2 = String::new();
-
-    file1.read_to_string(&mut buf1)?;
-    file2.read_to_string(&mut buf2)?;
-
-    assert_eq!(buf1, buf2);
-    Ok(())
+/// Starts a server that keeps its files in memory, preloaded with `files/hello.txt`.
+fn start_memory_server() -> Result<(SocketAddr, MemoryStorage)> {
+    let storage = MemoryStorage::new();
+    storage.insert("files/hello.txt", read_file("./files/hello.txt")?);
+    let addr = start_configured_server(TftpServerBuilder::new().storage(storage.clone()))?;
+    Ok((addr, storage))
 }
 
-fn timeout_test(server_addr: &SocketAddr) -> Result<()> {
+fn timeout_test() -> Result<()> {
+    let (server_addr, storage) = start_memory_server()?;
     let socket = create_socket(None)?;
     let init_packet = Packet::WRQ {
         filename: "hello.txt".to_string(),
-        mode: "octet".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
     };
     socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
 
     let mut buf = [0; MAX_PACKET_SIZE];
     let amt = socket.recv(&mut buf)?;
-    let reply_packet = Packet::read(PacketData::new(buf, amt))?;
+    let reply_packet = Packet::read(PacketData::new(&buf, amt))?;
     assert_eq!(reply_packet, Packet::ACK(0));
 
-
-    let mut buf = [0; MAX_PACKET_SIZE];
-    let amt = socket.recv(&mut buf)?;
-    let reply_packet = Packet::read(PacketData::new(buf, amt))?;
+    let (reply_packet, src) = recv_packet(&socket)?;
     assert_eq!(reply_packet, Packet::ACK(0));
 
-    assert!(fs::metadata("./hello.txt").is_ok());
-    assert!(fs::remove_file("./hello.txt").is_ok());
+    abort_transfer(&socket, &src)?;
+    assert!(storage.get("hello.txt").is_none());
+    Ok(())
+}
+
+fn memory_storage_stat_test() -> Result<()> {
+    let storage = MemoryStorage::new();
+    storage.insert("dir/file.txt", &b"contents"[..]);
+    // Paths are looked up the same way as when the file is opened.
+    assert_eq!(storage.stat("./dir//file.txt")?.len, 8);
+    storage.open("./dir//file.txt")?;
+    assert!(storage.stat("dir/other.txt").is_err());
     Ok(())
 }
 
-fn wrq_initial_ack_test(server_addr: &SocketAddr) -> Result<()> {
+fn wrq_initial_ack_test(server_addr: &SocketAddr, storage: &MemoryStorage) -> Result<()> {
     let input = Packet::WRQ {
         filename: "hello.txt".to_string(),
-        mode: "octet".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
     };
     let expected = Packet::ACK(0);
 
     let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
     socket.send_to(input.bytes()?.to_slice(), server_addr)?;
 
-    let mut buf = [0; MAX_PACKET_SIZE];
-    let amt = socket.recv(&mut buf)?;
-    assert_eq!(Packet::read(PacketData::new(buf, amt))?, expected);
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, expected);
 
-    // Test that hello.txt was created and remove hello.txt
-    assert!(fs::metadata("./hello.txt").is_ok());
-    assert!(fs::remove_file("./hello.txt").is_ok());
+    // Test that hello.txt is not created before the last block arrives
+    assert!(storage.get("hello.txt").is_none());
+    abort_transfer(&socket, &src)?;
     Ok(())
 }
 
 fn rrq_initial_data_test(server_addr: &SocketAddr) -> Result<()> {
     let input = Packet::RRQ {
         filename: "./files/hello.txt".to_string(),
-        mode: "octet".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
     };
     let mut file = File::open("./files/hello.txt")?;
     let mut buf = [0; 512];
     let amount = file.read(&mut buf)?;
     let expected = Packet::DATA {
         block_num: 1,
-        data: DataBytes(buf),
+        data: DataBytes(buf[0..amount].to_vec()),
         len: amount,
     };
 
@@ -105,15 +122,60 @@ fn rrq_initial_data_test(server_addr: &SocketAddr) -> Result<()> {
 
     let mut buf = [0; MAX_PACKET_SIZE];
     let amt = socket.recv(&mut buf)?;
-    assert_eq!(Packet::read(PacketData::new(buf, amt))?, expected);
+    assert_eq!(Packet::read(PacketData::new(&buf, amt))?, expected);
+    Ok(())
+}
+
+fn rrq_unknown_option_test(server_addr: &SocketAddr) -> Result<()> {
+    let input = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("foobar", "1")],
+    };
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    socket.send_to(input.bytes()?.to_slice(), server_addr)?;
+
+    // Unknown options are ignored so the server replies with the first data packet.
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let amt = socket.recv(&mut buf)?;
+    let packet = Packet::read(PacketData::new(&buf, amt))?;
+    if let Packet::DATA { block_num, .. } = packet {
+        assert_eq!(block_num, 1);
+    } else {
+        panic!("Packet has to be data packet, got: {:?}", packet);
+    }
     Ok(())
 }
 
-fn wrq_whole_file_test(server_addr: &SocketAddr) -> Result<()> {
+fn rrq_oack_test(server_addr: &SocketAddr) -> Result<()> {
+    let input = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("BLKSIZE", "1024"),
+                      TftpOption::new("foobar", "1"),
+                      TftpOption::new("blksize", "512")],
+    };
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    socket.send_to(input.bytes()?.to_slice(), server_addr)?;
+
+    // Only the first of the repeated supported options is acknowledged,
+    // with its name in lowercase, and the unknown option is dropped.
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::OACK(vec![TftpOption::new("blksize", "1024")]));
+    socket.send_to(Packet::ACK(0).bytes()?.to_slice(), src)?;
+    assert!(matches!(recv_packet(&socket)?.0, Packet::DATA { block_num: 1, .. }));
+    abort_transfer(&socket, &src)
+}
+
+fn wrq_whole_file_test() -> Result<()> {
+    let (server_addr, storage) = start_memory_server()?;
     let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
     let init_packet = Packet::WRQ {
         filename: "hello.txt".to_string(),
-        mode: "octet".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
     };
     socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
 
@@ -125,7 +187,7 @@ fn wrq_whole_file_test(server_addr: &SocketAddr) -> Result<()> {
             let mut reply_buf = [0; MAX_PACKET_SIZE];
             let (amt, src) = socket.recv_from(&mut reply_buf)?;
             recv_src = src;
-            let reply_packet = Packet::read(PacketData::new(reply_buf, amt))?;
+            let reply_packet = Packet::read(PacketData::new(&reply_buf, amt))?;
 
             assert_eq!(reply_packet, Packet::ACK(block_num));
             incr_block_num(&mut block_num);
@@ -134,26 +196,23 @@ fn wrq_whole_file_test(server_addr: &SocketAddr) -> Result<()> {
             let mut buf = [0; 512];
             let amount = match file.read(&mut buf) {
                 Err(_) => break,
-                Ok(i) if i == 0 => break,
+                Ok(0) => break,
                 Ok(i) => i,
             };
             let data_packet = Packet::DATA {
-                block_num: block_num,
-                data: DataBytes(buf),
+                block_num,
+                data: DataBytes(buf.to_vec()),
                 len: amount,
             };
-            socket.send_to(data_packet.bytes()?.to_slice(), &src)?;
+            socket.send_to(data_packet.bytes()?.to_slice(), src)?;
         }
 
         // Would cause server to have an error if this is received.
         // Used to test if connection is closed.
-        socket.send_to(&[1, 2, 3], &recv_src)?;
+        socket.send_to(&[1, 2, 3], recv_src)?;
     }
 
-    assert!(fs::metadata("./hello.txt").is_ok());
-    let (mut f1, mut f2) = (File::open("./hello.txt")?, File::open("./files/hello.txt")?);
-    check_similar_files(&mut f1, &mut f2)?;
-    assert!(fs::remove_file("./hello.txt").is_ok());
+    assert_eq!(storage.get("hello.txt"), Some(read_file("./files/hello.txt")?));
     Ok(())
 }
 
@@ -161,25 +220,26 @@ fn rrq_whole_file_test(server_addr: &SocketAddr) -> Result<()> {
     let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
     let init_packet = Packet::RRQ {
         filename: "./files/hello.txt".to_string(),
-        mode: "octet".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
     };
     socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
 
+    let mut contents = Vec::new();
     {
-        let mut file = File::create("./hello.txt")?;
         let mut client_block_num = 1;
         let mut recv_src;
         loop {
             let mut reply_buf = [0; MAX_PACKET_SIZE];
             let (amt, src) = socket.recv_from(&mut reply_buf)?;
             recv_src = src;
-            let reply_packet = Packet::read(PacketData::new(reply_buf, amt))?;
+            let reply_packet = Packet::read(PacketData::new(&reply_buf, amt))?;
             if let Packet::DATA { block_num, data, len } = reply_packet {
                 assert_eq!(client_block_num, block_num);
-                file.write(&data.0[0..len])?;
+                contents.extend_from_slice(&data.0[0..len]);
 
                 let ack_packet = Packet::ACK(client_block_num);
-                socket.send_to(ack_packet.bytes()?.to_slice(), &src)?;
+                socket.send_to(ack_packet.bytes()?.to_slice(), src)?;
 
                 incr_block_num(&mut client_block_num);
 
@@ -193,31 +253,1933 @@ fn rrq_whole_file_test(server_addr: &SocketAddr) -> Result<()> {
 
         // Would cause server to have an error if this is received.
         // Used to test if connection is closed.
-        socket.send_to(&[1, 2, 3], &recv_src)?;
+        socket.send_to(&[1, 2, 3], recv_src)?;
+    }
+
+    assert_eq!(contents, read_file("./files/hello.txt")?);
+    Ok(())
+}
+
+fn rrq_blksize_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("BLKSIZE", "1428")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let (amt, src) = socket.recv_from(&mut buf)?;
+    let reply_packet = Packet::read(PacketData::new(&buf, amt))?;
+    assert_eq!(reply_packet,
+               Packet::OACK(vec![TftpOption::new("blksize", "1428")]));
+    socket.send_to(Packet::ACK(0).bytes()?.to_slice(), src)?;
+
+    let mut contents = Vec::new();
+    let mut client_block_num = 1;
+    loop {
+        let mut reply_buf = [0; MAX_PACKET_SIZE];
+        let (amt, src) = socket.recv_from(&mut reply_buf)?;
+        let reply_packet = Packet::read(PacketData::new(&reply_buf, amt))?;
+        if let Packet::DATA { block_num, data, len } = reply_packet {
+            assert_eq!(client_block_num, block_num);
+            assert!(len <= 1428);
+            contents.extend_from_slice(&data.0[0..len]);
+            socket.send_to(Packet::ACK(client_block_num).bytes()?.to_slice(), src)?;
+            incr_block_num(&mut client_block_num);
+
+            if len < 1428 {
+                break;
+            }
+        } else {
+            panic!("Reply packet is not a data packet");
+        }
+    }
+
+    let mut expected = Vec::new();
+    File::open("./files/hello.txt")?.read_to_end(&mut expected)?;
+    assert_eq!(contents, expected);
+    Ok(())
+}
+
+fn rrq_invalid_blksize_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("blksize", "4")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    // Block sizes below 8 are not acknowledged so the default block size is used.
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let amt = socket.recv(&mut buf)?;
+    let packet = Packet::read(PacketData::new(&buf, amt))?;
+    if let Packet::DATA { block_num, len, .. } = packet {
+        assert_eq!(block_num, 1);
+        assert_eq!(len, 512);
+    } else {
+        panic!("Packet has to be data packet, got: {:?}", packet);
+    }
+    Ok(())
+}
+
+fn wrq_blksize_test() -> Result<()> {
+    let (server_addr, storage) = start_memory_server()?;
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("blksize", "1024")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let (amt, src) = socket.recv_from(&mut buf)?;
+    let reply_packet = Packet::read(PacketData::new(&buf, amt))?;
+    assert_eq!(reply_packet,
+               Packet::OACK(vec![TftpOption::new("blksize", "1024")]));
+
+    let mut file = File::open("./files/hello.txt")?;
+    let mut block_num = 0;
+    loop {
+        incr_block_num(&mut block_num);
+        let mut buf = [0; 1024];
+        let amount = file.read(&mut buf)?;
+        let data_packet = Packet::DATA {
+            block_num,
+            data: DataBytes(buf.to_vec()),
+            len: amount,
+        };
+        socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+
+        let mut reply_buf = [0; MAX_PACKET_SIZE];
+        let amt = socket.recv(&mut reply_buf)?;
+        let reply_packet = Packet::read(PacketData::new(&reply_buf, amt))?;
+        assert_eq!(reply_packet, Packet::ACK(block_num));
+
+        if amount < 1024 {
+            break;
+        }
+    }
+
+    assert_eq!(storage.get("hello.txt"), Some(read_file("./files/hello.txt")?));
+    Ok(())
+}
+
+fn rrq_tsize_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("tsize", "0")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    let file_size = fs::metadata("./files/hello.txt")?.len();
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let amt = socket.recv(&mut buf)?;
+    let reply_packet = Packet::read(PacketData::new(&buf, amt))?;
+    assert_eq!(reply_packet,
+               Packet::OACK(vec![TftpOption::new("tsize", file_size.to_string())]));
+    Ok(())
+}
+
+/// Sends a WRQ announcing the given tsize and checks that it is refused.
+fn wrq_tsize_refused(server_addr: &SocketAddr, tsize: u64) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("tsize", tsize.to_string())],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let amt = socket.recv(&mut buf)?;
+    let packet = Packet::read(PacketData::new(&buf, amt))?;
+    if let Packet::ERROR { code, .. } = packet {
+        assert_eq!(code, ErrorCode::DiskFull);
+    } else {
+        panic!("Packet has to be error packet, got: {:?}", packet);
+    }
+    assert!(fs::metadata("./hello.txt").is_err());
+    Ok(())
+}
+
+fn wrq_tsize_no_space_test(server_addr: &SocketAddr) -> Result<()> {
+    wrq_tsize_refused(server_addr, 1 << 62)
+}
+
+fn wrq_tsize_limit_test() -> Result<()> {
+    let server_addr = start_configured_server(TftpServerBuilder::new().max_upload_size(1024))?;
+    wrq_tsize_refused(&server_addr, 1025)?;
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("tsize", "1024")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet,
+               Packet::OACK(vec![TftpOption::new("tsize", "1024")]));
+
+    abort_transfer(&socket, &src)?;
+    Ok(())
+}
+
+fn wrq_size_limit_test() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .max_upload_size(1000)
+        .storage(storage.clone()))?;
+
+    // An upload without tsize is aborted once it grows past the limit.
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "large.bin".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::ACK(0));
+
+    for block_num in 1..3 {
+        let data_packet = Packet::DATA {
+            block_num,
+            data: DataBytes(vec![1; 512]),
+            len: 512,
+        };
+        socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+    }
+    assert_eq!(recv_packet(&socket)?.0, Packet::ACK(1));
+    match recv_packet(&socket)?.0 {
+        Packet::ERROR { code, .. } => assert_eq!(code, ErrorCode::DiskFull),
+        packet => panic!("Packet has to be error packet, got: {:?}", packet),
+    }
+    assert_eq!(storage.get("large.bin"), None);
+    Ok(())
+}
+
+fn adaptive_timeout_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    // Measure a few quick round trips on the loopback interface.
+    let mut src = *server_addr;
+    for block_num in 1..6 {
+        let (reply_packet, reply_src) = recv_packet(&socket)?;
+        match reply_packet {
+            Packet::DATA { block_num: num, .. } => assert_eq!(num, block_num),
+            packet => panic!("Reply packet is not a data packet: {:?}", packet),
+        }
+        src = reply_src;
+        if block_num < 5 {
+            socket.send_to(Packet::ACK(block_num).bytes()?.to_slice(), src)?;
+        }
+    }
+
+    // Block 5 is not acknowledged, so it is resent well before the
+    // default timeout, and again after a longer backoff.
+    let start = Instant::now();
+    assert!(matches!(recv_packet(&socket)?.0, Packet::DATA { block_num: 5, .. }));
+    let first_resend = start.elapsed();
+    assert!(matches!(recv_packet(&socket)?.0, Packet::DATA { block_num: 5, .. }));
+    let second_resend = start.elapsed() - first_resend;
+    assert!(first_resend < Duration::from_secs(1),
+            "resent after {:?}",
+            first_resend);
+    assert!(second_resend > first_resend,
+            "resent after {:?}, then after {:?}",
+            first_resend,
+            second_resend);
+
+    abort_transfer(&socket, &src)
+}
+
+fn max_retries_test() -> Result<()> {
+    let server_addr = start_configured_server(TftpServerBuilder::new().max_retries(1))?;
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("timeout", "1")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::OACK(vec![TftpOption::new("timeout", "1")]));
+
+    // The OACK is resent once, then the silent client is given up on.
+    assert_eq!(recv_packet(&socket)?.0, reply_packet);
+    match recv_packet(&socket)?.0 {
+        Packet::ERROR { code, .. } => assert_eq!(code, ErrorCode::NotDefined),
+        packet => panic!("Packet has to be error packet, got: {:?}", packet),
+    }
+
+    // The connection is closed, so a late ACK is not answered.
+    socket.send_to(Packet::ACK(0).bytes()?.to_slice(), src)?;
+    socket.set_read_timeout(Some(Duration::from_secs(2)))?;
+    assert!(recv_packet(&socket).is_err());
+    Ok(())
+}
+
+fn timeout_option_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(2)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("timeout", "1"), TftpOption::new("blksize", "8")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    let expected = Packet::OACK(vec![TftpOption::new("timeout", "1"),
+                                     TftpOption::new("blksize", "8")]);
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let amt = socket.recv(&mut buf)?;
+    assert_eq!(Packet::read(PacketData::new(&buf, amt))?, expected);
+
+    // The OACK is resent after one second, before the socket's two second timeout.
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let amt = socket.recv(&mut buf)?;
+    assert_eq!(Packet::read(PacketData::new(&buf, amt))?, expected);
+    Ok(())
+}
+
+fn invalid_timeout_option_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("timeout", "256"), TftpOption::new("blksize", "8")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let amt = socket.recv(&mut buf)?;
+    assert_eq!(Packet::read(PacketData::new(&buf, amt))?,
+               Packet::OACK(vec![TftpOption::new("blksize", "8")]));
+    Ok(())
+}
+
+/// Receives a packet and returns it with the address it was sent from.
+fn recv_packet(socket: &UdpSocket) -> Result<(Packet, SocketAddr)> {
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let (amt, src) = socket.recv_from(&mut buf)?;
+    Ok((Packet::read(PacketData::new(&buf, amt))?, src))
+}
+
+fn rrq_windowsize_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("windowsize", "4"), TftpOption::new("blksize", "1024")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet,
+               Packet::OACK(vec![TftpOption::new("windowsize", "4"),
+                                 TftpOption::new("blksize", "1024")]));
+    socket.send_to(Packet::ACK(0).bytes()?.to_slice(), src)?;
+
+    // Pretend that block 2 of the first window was lost and acknowledge
+    // block 1, so the server starts the next window at block 2.
+    for expected in 1..5 {
+        if let (Packet::DATA { block_num, .. }, _) = recv_packet(&socket)? {
+            assert_eq!(block_num, expected);
+        } else {
+            panic!("Reply packet is not a data packet");
+        }
+    }
+    socket.send_to(Packet::ACK(1).bytes()?.to_slice(), src)?;
+
+    let mut contents = Vec::new();
+    File::open("./files/hello.txt")?.take(1024).read_to_end(&mut contents)?;
+    let mut client_block_num = 2;
+    let mut window_count = 0;
+    loop {
+        let (reply_packet, src) = recv_packet(&socket)?;
+        if let Packet::DATA { block_num, data, len } = reply_packet {
+            assert_eq!(client_block_num, block_num);
+            contents.extend_from_slice(&data.0[0..len]);
+            window_count += 1;
+
+            if window_count == 4 || len < 1024 {
+                socket.send_to(Packet::ACK(client_block_num).bytes()?.to_slice(), src)?;
+                window_count = 0;
+            }
+            incr_block_num(&mut client_block_num);
+
+            if len < 1024 {
+                break;
+            }
+        } else {
+            panic!("Reply packet is not a data packet");
+        }
+    }
+
+    let mut expected = Vec::new();
+    File::open("./files/hello.txt")?.read_to_end(&mut expected)?;
+    assert_eq!(contents, expected);
+    Ok(())
+}
+
+fn rrq_large_windowsize_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("windowsize", "65535")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    // The window is capped so that a transfer cannot buffer the whole file.
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::OACK(vec![TftpOption::new("windowsize", "64")]));
+    abort_transfer(&socket, &src)
+}
+
+fn wrq_windowsize_test() -> Result<()> {
+    let (server_addr, storage) = start_memory_server()?;
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("windowsize", "4")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet,
+               Packet::OACK(vec![TftpOption::new("windowsize", "4")]));
+
+    // The server only acknowledges the last block of each window.
+    let mut file = File::open("./files/hello.txt")?;
+    let mut block_num = 0;
+    loop {
+        let mut amount = 512;
+        for _ in 0..4 {
+            incr_block_num(&mut block_num);
+            let mut buf = [0; 512];
+            amount = file.read(&mut buf)?;
+            let data_packet = Packet::DATA {
+                block_num,
+                data: DataBytes(buf.to_vec()),
+                len: amount,
+            };
+            socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+
+            if amount < 512 {
+                break;
+            }
+        }
+
+        let (reply_packet, _) = recv_packet(&socket)?;
+        assert_eq!(reply_packet, Packet::ACK(block_num));
+
+        if amount < 512 {
+            break;
+        }
+    }
+
+    assert_eq!(storage.get("hello.txt"), Some(read_file("./files/hello.txt")?));
+    Ok(())
+}
+
+fn rrq_netascii_test() -> Result<()> {
+    let (server_addr, storage) = start_memory_server()?;
+    storage.insert("netascii.txt", &b"line one\nline two\rend\n"[..]);
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./netascii.txt".to_string(),
+        mode: Mode::NetAscii,
+        options: vec![TftpOption::new("blksize", "8"), TftpOption::new("tsize", "0")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    // The size of the translated file is not known up front, so tsize is not acknowledged.
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::OACK(vec![TftpOption::new("blksize", "8")]));
+    socket.send_to(Packet::ACK(0).bytes()?.to_slice(), src)?;
+
+    let mut contents = Vec::new();
+    loop {
+        if let (Packet::DATA { block_num, data, len }, src) = recv_packet(&socket)? {
+            contents.extend_from_slice(&data.0[0..len]);
+            socket.send_to(Packet::ACK(block_num).bytes()?.to_slice(), src)?;
+            if len < 8 {
+                break;
+            }
+        } else {
+            panic!("Reply packet is not a data packet");
+        }
     }
 
-    assert!(fs::metadata("./hello.txt").is_ok());
-    let (mut f1, mut f2) = (File::open("./hello.txt")?, File::open("./files/hello.txt")?);
-    check_similar_files(&mut f1, &mut f2)?;
-    assert!(fs::remove_file("./hello.txt").is_ok());
+    assert_eq!(contents, b"line one\r\nline two\r\0end\r\n".to_vec());
     Ok(())
 }
 
+fn wrq_netascii_test() -> Result<()> {
+    let (server_addr, storage) = start_memory_server()?;
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "netascii.txt".to_string(),
+        mode: Mode::NetAscii,
+        options: vec![TftpOption::new("blksize", "8")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (_, src) = recv_packet(&socket)?;
+
+    // The CR LF and CR NUL pairs are split across blocks.
+    let blocks: [&[u8]; 3] = [b"line 1\r\n", b"line 2\r\0", b"\r\n"];
+    for (i, block) in blocks.iter().enumerate() {
+        let block_num = i as u16 + 1;
+        let data_packet = Packet::DATA {
+            block_num,
+            data: DataBytes(block.to_vec()),
+            len: block.len(),
+        };
+        socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+        assert_eq!(recv_packet(&socket)?.0, Packet::ACK(block_num));
+    }
+
+    assert_eq!(storage.get("netascii.txt"), Some(b"line 1\nline 2\r\n".to_vec()));
+    Ok(())
+}
+
+fn unknown_mode_test(server_addr: &SocketAddr) -> Result<()> {
+    let mut bytes = vec![0, 1];
+    bytes.extend_from_slice(b"./files/hello.txt\0foo\0");
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    socket.send_to(&bytes, server_addr)?;
+
+    let (packet, _) = recv_packet(&socket)?;
+    if let Packet::ERROR { code, .. } = packet {
+        assert_eq!(code, ErrorCode::IllegalTFTP);
+    } else {
+        panic!("Packet has to be error packet, got: {:?}", packet);
+    }
+    Ok(())
+}
+
+fn mail_mode_test(server_addr: &SocketAddr) -> Result<()> {
+    let rrq = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Mail,
+        options: vec![],
+    };
+    request_refused(server_addr, rrq, ErrorCode::IllegalTFTP)?;
+    let wrq = Packet::WRQ {
+        filename: "mail.txt".to_string(),
+        mode: Mode::Mail,
+        options: vec![],
+    };
+    request_refused(server_addr, wrq, ErrorCode::IllegalTFTP)
+}
+
+/// Sends a request and checks that it is refused with the given error code.
+fn request_refused(server_addr: &SocketAddr, packet: Packet, expected: ErrorCode) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    socket.send_to(packet.bytes()?.to_slice(), server_addr)?;
+
+    let (packet, _) = recv_packet(&socket)?;
+    if let Packet::ERROR { code, .. } = packet {
+        assert_eq!(code, expected);
+    } else {
+        panic!("Packet has to be error packet, got: {:?}", packet);
+    }
+    Ok(())
+}
+
+fn root_dir_test() -> Result<()> {
+    let server_addr = start_configured_server(TftpServerBuilder::new().root_dir("./files"))?;
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    if let (Packet::DATA { block_num, .. }, _) = recv_packet(&socket)? {
+        assert_eq!(block_num, 1);
+    } else {
+        panic!("Reply packet is not a data packet");
+    }
+
+    // Files relative to the working directory are not visible.
+    let rrq = Packet::RRQ {
+        filename: "files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(&server_addr, rrq, ErrorCode::FileNotFound)
+}
+
+#[cfg(unix)]
+fn root_dir_symlink_test() -> Result<()> {
+    use std::os::unix::fs::symlink;
+
+    fs::create_dir_all("./symlink_root")?;
+    symlink("../files", "./symlink_root/escape")?;
+    symlink("../files/hello.txt", "./symlink_root/link.txt")?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .root_dir("./symlink_root"))?;
+
+    let requests = vec![Packet::RRQ {
+                            filename: "link.txt".to_string(),
+                            mode: Mode::Octet,
+                            options: vec![],
+                        },
+                        Packet::RRQ {
+                            filename: "escape/hello.txt".to_string(),
+                            mode: Mode::Octet,
+                            options: vec![],
+                        },
+                        Packet::WRQ {
+                            filename: "escape/new.txt".to_string(),
+                            mode: Mode::Octet,
+                            options: vec![],
+                        }];
+    for packet in requests {
+        request_refused(&server_addr, packet, ErrorCode::AccessViolation)?;
+    }
+    assert!(fs::metadata("./files/new.txt").is_err());
+
+    fs::remove_dir_all("./symlink_root")?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn root_dir_symlink_test() -> Result<()> {
+    Ok(())
+}
+
+fn filename_policy_test(server_addr: &SocketAddr) -> Result<()> {
+    let rejected = ["/etc/passwd",
+                    "../Cargo.toml",
+                    "files/../../Cargo.toml",
+                    "files/..",
+                    "..\\Cargo.toml",
+                    "files\\hello.txt",
+                    "hello\u{202e}txt.exe",
+                    "hello\u{200b}.txt",
+                    "hello\u{feff}.txt",
+                    "hello\t.txt",
+                    "hello\u{7f}.txt",
+                    ""];
+    for filename in &rejected {
+        let rrq = Packet::RRQ {
+            filename: filename.to_string(),
+            mode: Mode::Octet,
+            options: vec![],
+        };
+        request_refused(server_addr, rrq, ErrorCode::AccessViolation)?;
+
+        let wrq = Packet::WRQ {
+            filename: filename.to_string(),
+            mode: Mode::Octet,
+            options: vec![],
+        };
+        request_refused(server_addr, wrq, ErrorCode::AccessViolation)?;
+    }
+    Ok(())
+}
+
+fn read_only_test() -> Result<()> {
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .access_mode(AccessMode::ReadOnly))?;
+
+    let wrq = Packet::WRQ {
+        filename: "hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(&server_addr, wrq, ErrorCode::AccessViolation)?;
+    assert!(fs::metadata("./hello.txt").is_err());
+
+    rrq_initial_data_test(&server_addr)
+}
+
+fn write_only_test() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .access_mode(AccessMode::WriteOnly)
+        .storage(storage.clone()))?;
+
+    let rrq = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(&server_addr, rrq, ErrorCode::AccessViolation)?;
+
+    wrq_initial_ack_test(&server_addr, &storage)
+}
+
+/// Uploads the contents to the server with a WRQ in 512 byte blocks.
+fn upload(server_addr: &SocketAddr, filename: &str, contents: &[u8]) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: filename.to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::ACK(0));
+
+    let mut block_num = 0;
+    let mut blocks = contents.chunks(512).collect::<Vec<_>>();
+    if contents.len().is_multiple_of(512) {
+        blocks.push(&[]);
+    }
+    for block in blocks {
+        incr_block_num(&mut block_num);
+        let data_packet = Packet::DATA {
+            block_num,
+            data: DataBytes(block.to_vec()),
+            len: block.len(),
+        };
+        socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+        assert_eq!(recv_packet(&socket)?.0, Packet::ACK(block_num));
+    }
+
+    // Give the server time to complete the upload.
+    thread::sleep(Duration::from_millis(100));
+    Ok(())
+}
+
+/// Returns the contents of the file at the given path.
+fn read_file(path: &str) -> Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+fn write_policy_overwrite_test() -> Result<()> {
+    fs::create_dir_all("./overwrite_root")?;
+    File::create("./overwrite_root/config.txt")?.write_all(b"old contents that are longer")?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .root_dir("./overwrite_root")
+        .write_policy(WritePolicy::Overwrite))?;
+
+    upload(&server_addr, "config.txt", b"new contents")?;
+    assert_eq!(read_file("./overwrite_root/config.txt")?, b"new contents".to_vec());
+
+    fs::remove_dir_all("./overwrite_root")?;
+    Ok(())
+}
+
+fn temp_file_refused_test() -> Result<()> {
+    fs::create_dir_all("./temp_file_root")?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .root_dir("./temp_file_root"))?;
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "upload.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    assert_eq!(recv_packet(&socket)?.0, Packet::ACK(0));
+
+    // The temporary file of the upload in progress can not be downloaded.
+    let entry = fs::read_dir("./temp_file_root")?.next().expect("temporary file")?;
+    let temp_name = entry.file_name().to_string_lossy().into_owned();
+    request_refused(&server_addr,
+                    Packet::RRQ {
+                        filename: temp_name,
+                        mode: Mode::Octet,
+                        options: vec![],
+                    },
+                    ErrorCode::FileNotFound)?;
+
+    fs::remove_dir_all("./temp_file_root")?;
+    Ok(())
+}
+
+fn write_policy_reject_race_test() -> Result<()> {
+    fs::create_dir_all("./reject_root")?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .root_dir("./reject_root"))?;
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "config.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (_, src) = recv_packet(&socket)?;
+
+    // A file created while the upload is running is not replaced.
+    File::create("./reject_root/config.txt")?.write_all(b"new file")?;
+    let data_packet = Packet::DATA {
+        block_num: 1,
+        data: DataBytes(vec![1; 10]),
+        len: 10,
+    };
+    socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+    match recv_packet(&socket)?.0 {
+        Packet::ERROR { code, .. } => assert_eq!(code, ErrorCode::FileExists),
+        packet => panic!("Packet has to be error packet, got: {:?}", packet),
+    }
+    assert_eq!(read_file("./reject_root/config.txt")?, b"new file".to_vec());
+    assert_eq!(fs::read_dir("./reject_root")?.count(), 1);
+
+    fs::remove_dir_all("./reject_root")?;
+    Ok(())
+}
+
+fn write_policy_replace_test() -> Result<()> {
+    fs::create_dir_all("./replace_root")?;
+    File::create("./replace_root/config.txt")?.write_all(b"old contents")?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .root_dir("./replace_root")
+        .write_policy(WritePolicy::Replace))?;
+
+    // The existing file is untouched until the transfer completes.
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "config.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (_, src) = recv_packet(&socket)?;
+    let data_packet = Packet::DATA {
+        block_num: 1,
+        data: DataBytes(vec![1; 512]),
+        len: 512,
+    };
+    socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+    assert_eq!(recv_packet(&socket)?.0, Packet::ACK(1));
+    assert_eq!(read_file("./replace_root/config.txt")?, b"old contents".to_vec());
+
+    let data_packet = Packet::DATA {
+        block_num: 2,
+        data: DataBytes(vec![2; 10]),
+        len: 10,
+    };
+    socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+    assert_eq!(recv_packet(&socket)?.0, Packet::ACK(2));
+    thread::sleep(Duration::from_millis(100));
+
+    let mut expected = vec![1; 512];
+    expected.extend_from_slice(&[2; 10]);
+    assert_eq!(read_file("./replace_root/config.txt")?, expected);
+    assert_eq!(fs::read_dir("./replace_root")?.count(), 1);
+
+    fs::remove_dir_all("./replace_root")?;
+    Ok(())
+}
+
+fn write_policy_versioned_test() -> Result<()> {
+    fs::create_dir_all("./versioned_root")?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .root_dir("./versioned_root")
+        .write_policy(WritePolicy::Versioned(2)))?;
+
+    for contents in &["first", "second", "third", "fourth"] {
+        upload(&server_addr, "config.txt", contents.as_bytes())?;
+    }
+    assert_eq!(read_file("./versioned_root/config.txt")?, b"fourth".to_vec());
+    assert_eq!(read_file("./versioned_root/config.txt.1")?, b"third".to_vec());
+    assert_eq!(read_file("./versioned_root/config.txt.2")?, b"second".to_vec());
+    assert!(fs::metadata("./versioned_root/config.txt.3").is_err());
+    assert_eq!(fs::read_dir("./versioned_root")?.count(), 3);
+
+    fs::remove_dir_all("./versioned_root")?;
+    Ok(())
+}
+
+/// Aborts a transfer with an ERROR packet and waits for the server to close it.
+fn abort_transfer(socket: &UdpSocket, src: &SocketAddr) -> Result<()> {
+    let error_packet = Packet::ERROR {
+        code: ErrorCode::NotDefined,
+        msg: "Aborted".to_string(),
+    };
+    socket.send_to(error_packet.bytes()?.to_slice(), src)?;
+    thread::sleep(Duration::from_millis(100));
+    Ok(())
+}
+
+fn wrq_duplicate_data_test() -> Result<()> {
+    fs::create_dir_all("./duplicate_root")?;
+    let server_addr = start_configured_server(TftpServerBuilder::new().root_dir("./duplicate_root"))?;
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "duplicate.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::ACK(0));
+
+    let block = |block_num: u16, len: usize| {
+        Packet::DATA {
+            block_num,
+            data: DataBytes(vec![block_num as u8; len]),
+            len,
+        }
+    };
+    // Duplicated and reordered blocks are answered with an
+    // ACK for the last block received in order.
+    let exchanges = vec![(block(1, 512), 1),
+                         (block(1, 512), 1),
+                         (block(3, 100), 1),
+                         (block(2, 512), 2),
+                         (block(2, 512), 2),
+                         (block(3, 100), 3)];
+    for (packet, ack) in exchanges {
+        socket.send_to(packet.bytes()?.to_slice(), src)?;
+        assert_eq!(recv_packet(&socket)?.0, Packet::ACK(ack));
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    // Every block was written exactly once.
+    let mut expected = vec![1; 512];
+    expected.extend_from_slice(&[2; 512]);
+    expected.extend_from_slice(&[3; 100]);
+    assert_eq!(read_file("./duplicate_root/duplicate.txt")?, expected);
+
+    fs::remove_dir_all("./duplicate_root")?;
+    Ok(())
+}
+
+fn rrq_duplicate_ack_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("timeout", "3")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::OACK(vec![TftpOption::new("timeout", "3")]));
+    socket.send_to(Packet::ACK(0).bytes()?.to_slice(), src)?;
+    assert!(matches!(recv_packet(&socket)?.0, Packet::DATA { block_num: 1, .. }));
+    socket.send_to(Packet::ACK(1).bytes()?.to_slice(), src)?;
+    assert!(matches!(recv_packet(&socket)?.0, Packet::DATA { block_num: 2, .. }));
+
+    // Duplicate ACKs for the OACK and block 1 do not cause DATA to be resent.
+    socket.send_to(Packet::ACK(0).bytes()?.to_slice(), src)?;
+    socket.send_to(Packet::ACK(1).bytes()?.to_slice(), src)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    assert!(recv_packet(&socket).is_err());
+
+    // The transfer continues normally afterwards.
+    socket.set_read_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+    socket.send_to(Packet::ACK(2).bytes()?.to_slice(), src)?;
+    assert!(matches!(recv_packet(&socket)?.0, Packet::DATA { block_num: 3, .. }));
+    abort_transfer(&socket, &src)
+}
+
+/// Sends a packet to a connection from a socket that is not part of the
+/// transfer and checks that it is answered with an unknown transfer ID error.
+fn send_from_rogue(packet: Packet, conn_addr: &SocketAddr) -> Result<()> {
+    let rogue = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    rogue.send_to(packet.bytes()?.to_slice(), conn_addr)?;
+    let (reply_packet, src) = recv_packet(&rogue)?;
+    assert_eq!(src, *conn_addr);
+    match reply_packet {
+        Packet::ERROR { code, .. } => assert_eq!(code, ErrorCode::UnknownID),
+        packet => panic!("Packet has to be error packet, got: {:?}", packet),
+    }
+    Ok(())
+}
+
+fn rrq_unknown_tid_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert!(matches!(reply_packet, Packet::DATA { block_num: 1, .. }));
+
+    // An ACK from a stranger does not advance the transfer.
+    send_from_rogue(Packet::ACK(1), &src)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    assert!(recv_packet(&socket).is_err());
+
+    socket.set_read_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+    socket.send_to(Packet::ACK(1).bytes()?.to_slice(), src)?;
+    assert!(matches!(recv_packet(&socket)?.0, Packet::DATA { block_num: 2, .. }));
+    abort_transfer(&socket, &src)
+}
+
+fn wrq_unknown_tid_test() -> Result<()> {
+    fs::create_dir_all("./unknown_tid_root")?;
+    let server_addr = start_configured_server(TftpServerBuilder::new().root_dir("./unknown_tid_root"))?;
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "upload.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::ACK(0));
+
+    // DATA from a stranger is not written, even if it would end the transfer.
+    let rogue_data = Packet::DATA {
+        block_num: 1,
+        data: DataBytes(vec![0; 10]),
+        len: 10,
+    };
+    send_from_rogue(rogue_data, &src)?;
+
+    let data_packet = Packet::DATA {
+        block_num: 1,
+        data: DataBytes(vec![1; 20]),
+        len: 20,
+    };
+    socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+    assert_eq!(recv_packet(&socket)?.0, Packet::ACK(1));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(read_file("./unknown_tid_root/upload.txt")?, vec![1; 20]);
+
+    fs::remove_dir_all("./unknown_tid_root")?;
+    Ok(())
+}
+
+/// The contents of a file with more blocks of 8 bytes than block numbers.
+fn rollover_contents() -> Vec<u8> {
+    (0..70_000 * 8 + 3).map(|i| (i % 251) as u8).collect()
+}
+
+fn rrq_rollover_test() -> Result<()> {
+    fs::create_dir_all("./rrq_rollover_root")?;
+    let expected = rollover_contents();
+    File::create("./rrq_rollover_root/large.bin")?.write_all(&expected)?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .root_dir("./rrq_rollover_root"))?;
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let options = vec![TftpOption::new("blksize", "8"), TftpOption::new("windowsize", "16")];
+    let init_packet = Packet::RRQ {
+        filename: "large.bin".to_string(),
+        mode: Mode::Octet,
+        options: options.clone(),
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::OACK(options));
+    socket.send_to(Packet::ACK(0).bytes()?.to_slice(), src)?;
+
+    // Block 65535 is followed by block 0 by default.
+    let mut contents = Vec::new();
+    let mut client_block_num = 1;
+    let mut blocks = 0;
+    loop {
+        let (reply_packet, _) = recv_packet(&socket)?;
+        let len = match reply_packet {
+            Packet::DATA { block_num, data, len } => {
+                assert_eq!(block_num, client_block_num);
+                contents.extend_from_slice(&data.0[0..len]);
+                len
+            }
+            packet => panic!("Reply packet is not a data packet: {:?}", packet),
+        };
+        blocks += 1;
+        if blocks % 16 == 0 || len < 8 {
+            socket.send_to(Packet::ACK(client_block_num).bytes()?.to_slice(), src)?;
+        }
+        if len < 8 {
+            break;
+        }
+        incr_block_num(&mut client_block_num);
+    }
+    assert!(blocks > 65535);
+    assert_eq!(contents, expected);
+
+    fs::remove_dir_all("./rrq_rollover_root")?;
+    Ok(())
+}
+
+fn wrq_rollover_test() -> Result<()> {
+    fs::create_dir_all("./wrq_rollover_root")?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .root_dir("./wrq_rollover_root"))?;
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let options = vec![TftpOption::new("blksize", "8"),
+                       TftpOption::new("windowsize", "16"),
+                       TftpOption::new("rollover", "1")];
+    let init_packet = Packet::WRQ {
+        filename: "large.bin".to_string(),
+        mode: Mode::Octet,
+        options: options.clone(),
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::OACK(options));
+
+    // Block 65535 is followed by block 1 as the client asked for.
+    let contents = rollover_contents();
+    let chunks = contents.chunks(8).collect::<Vec<_>>();
+    let mut block_num = 0;
+    for window in chunks.chunks(16) {
+        for chunk in window {
+            BlockRollover::One.incr(&mut block_num);
+            let data_packet = Packet::DATA {
+                block_num,
+                data: DataBytes(chunk.to_vec()),
+                len: chunk.len(),
+            };
+            socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+        }
+        assert_eq!(recv_packet(&socket)?.0, Packet::ACK(block_num));
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(read_file("./wrq_rollover_root/large.bin")?, contents);
+
+    fs::remove_dir_all("./wrq_rollover_root")?;
+    Ok(())
+}
+
+/// Sends a RRQ from a socket on the given address and checks that the
+/// first block is sent from a connection socket on the same address.
+fn rrq_from(client_ip: &str, server_addr: &SocketAddr) -> Result<()> {
+    let client_ip = client_ip.parse::<IpAddr>().unwrap();
+    let socket = create_socket_at(client_ip, Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert!(matches!(reply_packet, Packet::DATA { block_num: 1, .. }));
+    assert_eq!(src.ip(), client_ip);
+    assert!(src.port() != server_addr.port());
+    abort_transfer(&socket, &src)
+}
+
+/// Downloads a file with a RRQ from the given socket in 512 byte blocks.
+fn download(socket: &UdpSocket, server_addr: &SocketAddr, filename: &str) -> Result<Vec<u8>> {
+    let init_packet = Packet::RRQ {
+        filename: filename.to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+
+    let mut contents = Vec::new();
+    loop {
+        match recv_packet(socket)? {
+            (Packet::DATA { block_num, data, len }, src) => {
+                contents.extend_from_slice(&data.0[0..len]);
+                socket.send_to(Packet::ACK(block_num).bytes()?.to_slice(), src)?;
+                if len < 512 {
+                    return Ok(contents);
+                }
+            }
+            (packet, _) => panic!("Reply packet is not a data packet: {:?}", packet),
+        }
+    }
+}
+
+fn read_handler_test() -> Result<()> {
+    let storage = MemoryStorage::new();
+    storage.insert("pxelinux.cfg/default", &b"default"[..]);
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .storage(storage)
+        .read_handler(|path, addr| {
+            match path {
+                "pxelinux.cfg/default" => None,
+                "secret.cfg" => Some(Err(io::Error::from(io::ErrorKind::PermissionDenied))),
+                _ if path.starts_with("pxelinux.cfg/") => {
+                    Some(Ok(format!("{} for {}", path, addr.ip()).into_bytes()))
+                }
+                _ => None,
+            }
+        }))?;
+
+    // Generated files depend on the requested path and the client's address.
+    for client_ip in &["127.0.0.1", "127.0.0.2"] {
+        let ip = client_ip.parse::<IpAddr>().unwrap();
+        let socket = create_socket_at(ip, Some(Duration::from_secs(TIMEOUT)))?;
+        let contents = download(&socket, &server_addr, "pxelinux.cfg/01-aa-bb")?;
+        assert_eq!(contents, format!("pxelinux.cfg/01-aa-bb for {}", client_ip).into_bytes());
+    }
+
+    // Paths the handlers leave alone are read from the storage.
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    assert_eq!(download(&socket, &server_addr, "./pxelinux.cfg/default")?, b"default".to_vec());
+
+    let secret = Packet::RRQ {
+        filename: "secret.cfg".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(&server_addr, secret, ErrorCode::AccessViolation)?;
+    let missing = Packet::RRQ {
+        filename: "missing.cfg".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(&server_addr, missing, ErrorCode::FileNotFound)
+}
+
+/// How an upload taken by a write handler ended: the path, the client's
+/// address and the uploaded contents, or `None` if the upload was aborted.
+type UploadLog = Arc<Mutex<Vec<(String, SocketAddr, Option<Vec<u8>>)>>>;
+
+/// A sink that records the uploads it receives in a log.
+struct RecordingSink {
+    log: UploadLog,
+    path: String,
+    addr: SocketAddr,
+    contents: Vec<u8>,
+}
+
+impl Write for RecordingSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.contents.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StorageWriter for RecordingSink {
+    fn finalize(&mut self) -> io::Result<()> {
+        let contents = Some(self.contents.clone());
+        self.log.lock().unwrap().push((self.path.clone(), self.addr, contents));
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        self.log.lock().unwrap().push((self.path.clone(), self.addr, None));
+    }
+}
+
+fn write_handler_test() -> Result<()> {
+    let log = UploadLog::default();
+    let handler_log = log.clone();
+    let storage = MemoryStorage::new();
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .storage(storage.clone())
+        .write_handler(move |path, mode, addr| {
+            if !path.starts_with("configs/") {
+                return None;
+            }
+            if mode != Mode::Octet {
+                return Some(Err(io::Error::from(io::ErrorKind::PermissionDenied)));
+            }
+            let sink: Box<dyn StorageWriter> = Box::new(RecordingSink {
+                log: handler_log.clone(),
+                path: path.to_string(),
+                addr: *addr,
+                contents: Vec::new(),
+            });
+            Some(Ok(sink))
+        }))?;
+
+    // A completed upload is finalized with its contents.
+    upload(&server_addr, "./configs/router.cfg", b"hostname router")?;
+    let (path, _, contents) = log.lock().unwrap().remove(0);
+    assert_eq!(path, "configs/router.cfg");
+    assert_eq!(contents, Some(b"hostname router".to_vec()));
+
+    // An upload that is cut short is aborted.
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "configs/switch.cfg".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (_, src) = recv_packet(&socket)?;
+    abort_transfer(&socket, &src)?;
+    let (path, addr, contents) = log.lock().unwrap().remove(0);
+    assert_eq!(path, "configs/switch.cfg");
+    assert_eq!(addr, socket.local_addr()?);
+    assert_eq!(contents, None);
+
+    // The handler can refuse uploads and leave others to the storage.
+    let netascii = Packet::WRQ {
+        filename: "configs/switch.cfg".to_string(),
+        mode: Mode::NetAscii,
+        options: vec![],
+    };
+    request_refused(&server_addr, netascii, ErrorCode::AccessViolation)?;
+    upload(&server_addr, "notes.txt", b"notes")?;
+    assert_eq!(storage.paths(), vec!["notes.txt"]);
+    assert!(log.lock().unwrap().is_empty());
+    Ok(())
+}
+
+/// Returns the contents of a file spanning several blocks.
+fn archive_member_contents(seed: u8) -> Vec<u8> {
+    (0..1500u32).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// Writes the files of the tar archives used by the archive storage test.
+fn append_tar_members<W: Write>(builder: &mut tar::Builder<W>) -> io::Result<()> {
+    let contents = archive_member_contents(1);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, "./vmlinuz", &contents[..])?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(6);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, "cfg/default", &b"config"[..])
+}
+
+/// Writes a zip archive with a stored and a deflated file.
+fn write_zip(path: &str) -> zip::result::ZipResult<()> {
+    let mut writer = zip::ZipWriter::new(File::create(path)?);
+    let options = zip::write::FileOptions::default();
+    writer.start_file("stored.bin",
+                      options.compression_method(zip::CompressionMethod::Stored))?;
+    writer.write_all(&archive_member_contents(2))?;
+    writer.start_file("images/deflated.bin",
+                      options.compression_method(zip::CompressionMethod::Deflated))?;
+    writer.write_all(&archive_member_contents(3))?;
+    writer.finish()?;
+    Ok(())
+}
+
+fn archive_storage_test() -> Result<()> {
+    fs::create_dir_all("./archive_root")?;
+    let mut builder = tar::Builder::new(File::create("./archive_root/boot.tar")?);
+    append_tar_members(&mut builder)?;
+    builder.into_inner()?;
+    let encoder = GzEncoder::new(File::create("./archive_root/rescue.tar.gz")?,
+                                 Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    append_tar_members(&mut builder)?;
+    builder.into_inner()?.finish()?;
+    write_zip("./archive_root/netboot.zip").map_err(io::Error::from)?;
+    // Archives that cannot be read are skipped when the storage is created.
+    fs::write("./archive_root/broken.tar.gz", b"not an archive")?;
+
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .storage(ArchiveStorage::new("./archive_root")?))?;
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    for archive in &["boot", "rescue"] {
+        let vmlinuz = download(&socket, &server_addr, &format!("{}/vmlinuz", archive))?;
+        assert_eq!(vmlinuz, archive_member_contents(1));
+        let config = download(&socket, &server_addr, &format!("{}/cfg/default", archive))?;
+        assert_eq!(config, b"config".to_vec());
+    }
+    assert_eq!(download(&socket, &server_addr, "netboot/stored.bin")?,
+               archive_member_contents(2));
+    assert_eq!(download(&socket, &server_addr, "netboot/images/deflated.bin")?,
+               archive_member_contents(3));
+
+    // The size of a member is announced with the tsize option.
+    let init_packet = Packet::RRQ {
+        filename: "rescue/vmlinuz".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("tsize", "0")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::OACK(vec![TftpOption::new("tsize", "1500")]));
+    abort_transfer(&socket, &src)?;
+
+    let missing = Packet::RRQ {
+        filename: "boot/initrd".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(&server_addr, missing, ErrorCode::FileNotFound)?;
+    let upload = Packet::WRQ {
+        filename: "boot/initrd".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(&server_addr, upload, ErrorCode::AccessViolation)?;
+    let broken = Packet::RRQ {
+        filename: "broken/vmlinuz".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(&server_addr, broken, ErrorCode::FileNotFound)?;
+
+    // Members larger than the cache are decompressed for every request.
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .storage(ArchiveStorage::new("./archive_root")?.cache_size(1000)))?;
+    for _ in 0..2 {
+        let vmlinuz = download(&socket, &server_addr, "rescue/vmlinuz")?;
+        assert_eq!(vmlinuz, archive_member_contents(1));
+        let config = download(&socket, &server_addr, "rescue/cfg/default")?;
+        assert_eq!(config, b"config".to_vec());
+    }
+
+    // Archives are only indexed when the storage is created, so archives added
+    // later are not served and the members of changed archives are refused.
+    let mut builder = tar::Builder::new(File::create("./archive_root/late.tar")?);
+    append_tar_members(&mut builder)?;
+    builder.into_inner()?;
+    File::options().write(true).open("./archive_root/boot.tar")?
+        .set_modified(SystemTime::now() + Duration::from_secs(60))?;
+    for path in &["late/vmlinuz", "boot/vmlinuz"] {
+        let rrq = Packet::RRQ {
+            filename: path.to_string(),
+            mode: Mode::Octet,
+            options: vec![],
+        };
+        request_refused(&server_addr, rrq, ErrorCode::FileNotFound)?;
+    }
+    assert_eq!(download(&socket, &server_addr, "netboot/stored.bin")?,
+               archive_member_contents(2));
+
+    fs::remove_dir_all("./archive_root")?;
+    Ok(())
+}
+
+fn ipv6_test() -> Result<()> {
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .addr("[::1]:0".parse().unwrap()))?;
+    rrq_from("::1", &server_addr)
+}
+
+fn dual_stack_test() -> Result<()> {
+    // Find a port that is free on both the IPv4 and IPv6 wildcard addresses.
+    let port = UdpSocket::bind("[::]:0")?.local_addr()?.port();
+    let mut server = TftpServerBuilder::new()
+        .addr(SocketAddr::new("0.0.0.0".parse().unwrap(), port))
+        .addr(SocketAddr::new("::".parse().unwrap(), port))
+        .build()?;
+    let addrs = server.local_addrs()?;
+    assert_eq!(addrs.len(), 2);
+    assert!(addrs[0].is_ipv4() && addrs[1].is_ipv6());
+    thread::spawn(move || server.run());
+
+    rrq_from("127.0.0.1", &SocketAddr::new("127.0.0.1".parse().unwrap(), port))?;
+    rrq_from("::1", &SocketAddr::new("::1".parse().unwrap(), port))
+}
+
+/// Returns the first port of a range of free ports with the given length.
+fn free_port_range(len: u16) -> Result<u16> {
+    loop {
+        let start = UdpSocket::bind("127.0.0.1:0")?.local_addr()?.port();
+        if start > u16::MAX - len {
+            continue;
+        }
+        let free = (start..start + len).all(|port| UdpSocket::bind(("127.0.0.1", port)).is_ok());
+        if free {
+            return Ok(start);
+        }
+    }
+}
+
+/// Starts a RRQ and returns the client socket and the address of
+/// the connection socket that sent the first block.
+fn start_rrq(server_addr: &SocketAddr) -> Result<(UdpSocket, SocketAddr)> {
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert!(matches!(reply_packet, Packet::DATA { block_num: 1, .. }),
+            "Reply packet is not a data packet: {:?}",
+            reply_packet);
+    Ok((socket, src))
+}
+
+fn sequential_port_range_test() -> Result<()> {
+    let start = free_port_range(2)?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .port_range(start..=start + 1)
+        .port_allocation(PortAllocation::Sequential))?;
+
+    let (first, first_src) = start_rrq(&server_addr)?;
+    let (second, second_src) = start_rrq(&server_addr)?;
+    assert_eq!(first_src.port(), start);
+    assert_eq!(second_src.port(), start + 1);
+
+    // Requests are refused while the whole range is in use.
+    request_refused(&server_addr,
+                    Packet::RRQ {
+                        filename: "./files/hello.txt".to_string(),
+                        mode: Mode::Octet,
+                        options: vec![],
+                    },
+                    ErrorCode::NotDefined)?;
+
+    // The port of a closed connection is handed out again.
+    abort_transfer(&first, &first_src)?;
+    let (third, third_src) = start_rrq(&server_addr)?;
+    assert_eq!(third_src.port(), start);
+
+    abort_transfer(&second, &second_src)?;
+    abort_transfer(&third, &third_src)
+}
+
+fn random_port_range_test() -> Result<()> {
+    let start = free_port_range(3)?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .port_range(start..=start + 2))?;
+
+    let mut transfers = Vec::new();
+    for _ in 0..3 {
+        transfers.push(start_rrq(&server_addr)?);
+    }
+    let mut ports = transfers.iter().map(|&(_, src)| src.port()).collect::<Vec<_>>();
+    ports.sort();
+    assert_eq!(ports, vec![start, start + 1, start + 2]);
+
+    for (socket, src) in transfers {
+        abort_transfer(&socket, &src)?;
+    }
+    Ok(())
+}
+
+fn port_range_failed_start_test() -> Result<()> {
+    let start = free_port_range(2)?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .storage(BrokenStorage)
+        .port_range(start..=start + 1))?;
+
+    // The ports of transfers that fail to start are handed out again.
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    rrq_broken_file(&socket, &server_addr)?;
+    rrq_broken_file(&socket, &server_addr)?;
+    rrq_broken_file(&socket, &server_addr)?;
+    assert_eq!(download(&socket, &server_addr, "static.bin")?, b"static contents".to_vec());
+    Ok(())
+}
+
+fn single_port_test() -> Result<()> {
+    fs::create_dir_all("./single_port_root/files")?;
+    fs::copy("./files/hello.txt", "./single_port_root/files/hello.txt")?;
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .root_dir("./single_port_root")
+        .single_port(true))?;
+
+    // A read and a write are served from the server port at the same time.
+    let (reader, reader_src) = start_rrq(&server_addr)?;
+    assert_eq!(reader_src, server_addr);
+
+    let writer = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "upload.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    writer.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, writer_src) = recv_packet(&writer)?;
+    assert_eq!(reply_packet, Packet::ACK(0));
+    assert_eq!(writer_src, server_addr);
+
+    reader.send_to(Packet::ACK(1).bytes()?.to_slice(), server_addr)?;
+    let data_packet = Packet::DATA {
+        block_num: 1,
+        data: DataBytes(vec![1; 10]),
+        len: 10,
+    };
+    writer.send_to(data_packet.bytes()?.to_slice(), server_addr)?;
+
+    let (reply_packet, src) = recv_packet(&reader)?;
+    assert!(matches!(reply_packet, Packet::DATA { block_num: 2, .. }));
+    assert_eq!(src, server_addr);
+    let (reply_packet, src) = recv_packet(&writer)?;
+    assert_eq!(reply_packet, Packet::ACK(1));
+    assert_eq!(src, server_addr);
+
+    // The client's error is not answered.
+    abort_transfer(&reader, &server_addr)?;
+    assert_eq!(read_file("./single_port_root/upload.txt")?, vec![1; 10]);
+
+    // New requests from the same client are accepted after its transfer closed.
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    reader.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    assert!(matches!(recv_packet(&reader)?.0, Packet::DATA { block_num: 1, .. }));
+    abort_transfer(&reader, &server_addr)?;
+
+    fs::remove_dir_all("./single_port_root")?;
+    Ok(())
+}
+
+fn memory_storage_versioned_test() -> Result<()> {
+    let storage = MemoryStorage::new();
+    storage.insert("./config.txt", &b"first"[..]);
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .write_policy(WritePolicy::Versioned(2))
+        .storage(storage.clone()))?;
+
+    upload(&server_addr, "config.txt", b"second")?;
+    upload(&server_addr, "config.txt", b"third")?;
+    upload(&server_addr, "config.txt", b"fourth")?;
+
+    assert_eq!(storage.paths(), vec!["config.txt", "config.txt.1", "config.txt.2"]);
+    assert_eq!(storage.get("config.txt"), Some(b"fourth".to_vec()));
+    assert_eq!(storage.get("config.txt.1"), Some(b"third".to_vec()));
+    assert_eq!(storage.get("config.txt.2"), Some(b"second".to_vec()));
+    Ok(())
+}
+
+fn single_port_repeated_rrq_test() -> Result<()> {
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .storage(StaticStorage(b"static contents"))
+        .single_port(true))?;
+
+    // A resent RRQ is answered with the first block again.
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "static.bin".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.clone().bytes()?.to_slice(), server_addr)?;
+    let first = recv_packet(&socket)?;
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let second = recv_packet(&socket)?;
+    assert_eq!(first, second);
+    assert!(matches!(second, (Packet::DATA { block_num: 1, .. }, src) if src == server_addr));
+
+    socket.send_to(Packet::ACK(1).bytes()?.to_slice(), server_addr)?;
+    assert_eq!(download(&socket, &server_addr, "static.bin")?, b"static contents".to_vec());
+    Ok(())
+}
+
+fn single_port_repeated_last_data_test() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .storage(storage.clone())
+        .single_port(true))?;
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "first.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    assert_eq!(recv_packet(&socket)?, (Packet::ACK(0), server_addr));
+
+    // The last block is acknowledged again when the client resends it
+    // because it did not receive the ACK.
+    let data_packet = Packet::DATA {
+        block_num: 1,
+        data: DataBytes(vec![1; 10]),
+        len: 10,
+    };
+    socket.send_to(data_packet.clone().bytes()?.to_slice(), server_addr)?;
+    assert_eq!(recv_packet(&socket)?, (Packet::ACK(1), server_addr));
+    socket.send_to(data_packet.clone().bytes()?.to_slice(), server_addr)?;
+    assert_eq!(recv_packet(&socket)?, (Packet::ACK(1), server_addr));
+    assert_eq!(storage.get("first.txt"), Some(vec![1; 10]));
+
+    // The client's next request is served right away.
+    let init_packet = Packet::WRQ {
+        filename: "second.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    assert_eq!(recv_packet(&socket)?, (Packet::ACK(0), server_addr));
+    socket.send_to(data_packet.bytes()?.to_slice(), server_addr)?;
+    assert_eq!(recv_packet(&socket)?, (Packet::ACK(1), server_addr));
+    assert_eq!(storage.get("second.txt"), Some(vec![1; 10]));
+    Ok(())
+}
+
+fn wrq_repeated_last_data_test() -> Result<()> {
+    let (server_addr, storage) = start_memory_server()?;
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "repeated.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::ACK(0));
+
+    // The transfer socket acknowledges a resent last block again.
+    let data_packet = Packet::DATA {
+        block_num: 1,
+        data: DataBytes(vec![1; 10]),
+        len: 10,
+    };
+    socket.send_to(data_packet.clone().bytes()?.to_slice(), src)?;
+    assert_eq!(recv_packet(&socket)?, (Packet::ACK(1), src));
+    socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+    assert_eq!(recv_packet(&socket)?, (Packet::ACK(1), src));
+    assert_eq!(storage.get("repeated.txt"), Some(vec![1; 10]));
+    Ok(())
+}
+
+fn client_error_test(server_addr: &SocketAddr) -> Result<()> {
+    let (socket, src) = start_rrq(server_addr)?;
+    abort_transfer(&socket, &src)?;
+
+    // An error from the client closes the transfer without a reply.
+    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+    assert!(recv_packet(&socket).is_err());
+    Ok(())
+}
+
+fn single_port_failed_start_test() -> Result<()> {
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .storage(BrokenStorage)
+        .single_port(true))?;
+
+    // A transfer that fails before it started does not keep the client's
+    // later requests from being served.
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    rrq_broken_file(&socket, &server_addr)?;
+    let contents = download(&socket, &server_addr, "static.bin")?;
+    assert_eq!(contents, b"static contents".to_vec());
+    Ok(())
+}
+
+fn wrq_aborted_test() -> Result<()> {
+    fs::create_dir_all("./aborted_root")?;
+    let server_addr = start_configured_server(TftpServerBuilder::new().root_dir("./aborted_root"))?;
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "partial.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (_, src) = recv_packet(&socket)?;
+    let data_packet = Packet::DATA {
+        block_num: 1,
+        data: DataBytes(vec![1; 512]),
+        len: 512,
+    };
+    socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+    assert_eq!(recv_packet(&socket)?.0, Packet::ACK(1));
+
+    // The file does not appear under its name until the last block arrives.
+    assert!(fs::metadata("./aborted_root/partial.txt").is_err());
+
+    // Aborting the transfer removes the temporary file.
+    abort_transfer(&socket, &src)?;
+    assert_eq!(fs::read_dir("./aborted_root")?.count(), 0);
+
+    fs::remove_dir_all("./aborted_root")?;
+    Ok(())
+}
+
+/// A storage that serves the same contents for every path and refuses uploads.
+struct StaticStorage(&'static [u8]);
+
+impl Storage for StaticStorage {
+    fn open(&self, _path: &str) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.0))
+    }
+
+    fn stat(&self, _path: &str) -> io::Result<Metadata> {
+        Ok(Metadata { len: self.0.len() as u64 })
+    }
+
+    fn create(&self, _path: &str, _policy: WritePolicy) -> io::Result<Box<dyn StorageWriter>> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "uploads are not supported"))
+    }
+}
+
+/// A reader that fails on every read.
+struct BrokenReader;
+
+impl Read for BrokenReader {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::other("broken medium"))
+    }
+}
+
+/// A writer that accepts every block but fails to finalize the upload.
+struct BrokenWriter;
+
+impl Write for BrokenWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StorageWriter for BrokenWriter {
+    fn finalize(&mut self) -> io::Result<()> {
+        Err(io::Error::other("broken medium"))
+    }
+
+    fn abort(&mut self) {}
+}
+
+/// A storage like `StaticStorage` whose file `broken.bin` can neither be read
+/// nor written, and whose file `unwritable.bin` cannot be created.
+struct BrokenStorage;
+
+impl Storage for BrokenStorage {
+    fn open(&self, path: &str) -> io::Result<Box<dyn Read + Send>> {
+        match path {
+            "broken.bin" => Ok(Box::new(BrokenReader)),
+            path => StaticStorage(b"static contents").open(path),
+        }
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        StaticStorage(b"static contents").stat(path)
+    }
+
+    fn create(&self, path: &str, policy: WritePolicy) -> io::Result<Box<dyn StorageWriter>> {
+        match path {
+            "broken.bin" => Ok(Box::new(BrokenWriter)),
+            "unwritable.bin" => Err(io::Error::other("read-only medium")),
+            path => StaticStorage(b"static contents").create(path, policy),
+        }
+    }
+}
+
+/// Sends a RRQ for `broken.bin` from the socket and checks that it is
+/// answered with an error.
+fn rrq_broken_file(socket: &UdpSocket, server_addr: &SocketAddr) -> Result<()> {
+    let init_packet = Packet::RRQ {
+        filename: "broken.bin".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    match recv_packet(socket)?.0 {
+        Packet::ERROR { code, .. } => assert_eq!(code, ErrorCode::NotDefined),
+        packet => panic!("Packet has to be error packet, got: {:?}", packet),
+    }
+    Ok(())
+}
+
+fn rrq_read_error_test() -> Result<()> {
+    let server_addr = start_configured_server(TftpServerBuilder::new().storage(BrokenStorage))?;
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    rrq_broken_file(&socket, &server_addr)
+}
+
+fn wrq_write_error_test() -> Result<()> {
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .storage(BrokenStorage)
+        .write_policy(WritePolicy::Replace))?;
+
+    // Errors without a matching error code are still reported to the client.
+    let wrq = Packet::WRQ {
+        filename: "unwritable.bin".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(&server_addr, wrq, ErrorCode::NotDefined)?;
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::WRQ {
+        filename: "broken.bin".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (_, src) = recv_packet(&socket)?;
+    let data_packet = Packet::DATA {
+        block_num: 1,
+        data: DataBytes(vec![1; 10]),
+        len: 10,
+    };
+    socket.send_to(data_packet.bytes()?.to_slice(), src)?;
+    match recv_packet(&socket)?.0 {
+        Packet::ERROR { code, .. } => assert_eq!(code, ErrorCode::NotDefined),
+        packet => panic!("Packet has to be error packet, got: {:?}", packet),
+    }
+    Ok(())
+}
+
+fn directory_request_test(server_addr: &SocketAddr) -> Result<()> {
+    let rrq = Packet::RRQ {
+        filename: "./files".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(server_addr, rrq, ErrorCode::FileNotFound)?;
+    let wrq = Packet::WRQ {
+        filename: "files".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(server_addr, wrq, ErrorCode::AccessViolation)
+}
+
+fn custom_storage_test() -> Result<()> {
+    let server_addr = start_configured_server(TftpServerBuilder::new()
+        .write_policy(WritePolicy::Overwrite)
+        .storage(StaticStorage(b"static contents")))?;
+
+    let socket = create_socket(Some(Duration::from_secs(TIMEOUT)))?;
+    let init_packet = Packet::RRQ {
+        filename: "any/file.bin".to_string(),
+        mode: Mode::Octet,
+        options: vec![TftpOption::new("tsize", "0")],
+    };
+    socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
+    let (reply_packet, src) = recv_packet(&socket)?;
+    assert_eq!(reply_packet, Packet::OACK(vec![TftpOption::new("tsize", "15")]));
+    socket.send_to(Packet::ACK(0).bytes()?.to_slice(), src)?;
+    let (reply_packet, _) = recv_packet(&socket)?;
+    assert_eq!(reply_packet,
+               Packet::DATA {
+                   block_num: 1,
+                   data: DataBytes(b"static contents".to_vec()),
+                   len: 15,
+               });
+    socket.send_to(Packet::ACK(1).bytes()?.to_slice(), src)?;
+
+    let init_packet = Packet::WRQ {
+        filename: "upload.txt".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
+    };
+    request_refused(&server_addr, init_packet, ErrorCode::AccessViolation)
+}
+
 fn wrq_file_exists_test(server_addr: &SocketAddr) -> Result<()> {
     let socket = create_socket(None)?;
     let init_packet = Packet::WRQ {
         filename: "./files/hello.txt".to_string(),
-        mode: "octet".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
     };
     socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
 
     let mut buf = [0; MAX_PACKET_SIZE];
     let amt = socket.recv(&mut buf)?;
-    let packet = Packet::read(PacketData::new(buf, amt))?;
+    let packet = Packet::read(PacketData::new(&buf, amt))?;
     if let Packet::ERROR { code, .. } = packet {
         assert_eq!(code, ErrorCode::FileExists);
     } else {
-        panic!(format!("Packet has to be error packet, got: {:?}", packet));
+        panic!("Packet has to be error packet, got: {:?}", packet);
     }
     Ok(())
 }
@@ -226,17 +2188,18 @@ fn rrq_file_not_found_test(server_addr: &SocketAddr) -> Result<()> {
     let socket = create_socket(None)?;
     let init_packet = Packet::RRQ {
         filename: "./hello.txt".to_string(),
-        mode: "octet".to_string(),
+        mode: Mode::Octet,
+        options: vec![],
     };
     socket.send_to(init_packet.bytes()?.to_slice(), server_addr)?;
 
     let mut buf = [0; MAX_PACKET_SIZE];
     let amt = socket.recv(&mut buf)?;
-    let packet = Packet::read(PacketData::new(buf, amt))?;
+    let packet = Packet::read(PacketData::new(&buf, amt))?;
     if let Packet::ERROR { code, .. } = packet {
         assert_eq!(code, ErrorCode::FileNotFound);
     } else {
-        panic!(format!("Packet has to be error packet, got: {:?}", packet));
+        panic!("Packet has to be error packet, got: {:?}", packet);
     }
     Ok(())
 }
@@ -244,13 +2207,71 @@ fn rrq_file_not_found_test(server_addr: &SocketAddr) -> Result<()> {
 fn main() {
     env_logger::init().unwrap();
     let server_addr = start_server().unwrap();
+    let (memory_addr, storage) = start_memory_server().unwrap();
     thread::sleep(Duration::from_millis(1000));
-    wrq_initial_ack_test(&server_addr).unwrap();
+    wrq_initial_ack_test(&memory_addr, &storage).unwrap();
+    memory_storage_stat_test().unwrap();
     rrq_initial_data_test(&server_addr).unwrap();
+    rrq_unknown_option_test(&server_addr).unwrap();
+    rrq_oack_test(&server_addr).unwrap();
     thread::sleep(Duration::from_millis(1000));
-    wrq_whole_file_test(&server_addr).unwrap();
+    wrq_whole_file_test().unwrap();
     rrq_whole_file_test(&server_addr).unwrap();
-    timeout_test(&server_addr).unwrap();
+    rrq_blksize_test(&server_addr).unwrap();
+    rrq_invalid_blksize_test(&server_addr).unwrap();
+    wrq_blksize_test().unwrap();
+    rrq_tsize_test(&server_addr).unwrap();
+    wrq_tsize_no_space_test(&server_addr).unwrap();
+    wrq_tsize_limit_test().unwrap();
+    wrq_size_limit_test().unwrap();
+    rrq_windowsize_test(&server_addr).unwrap();
+    rrq_large_windowsize_test(&server_addr).unwrap();
+    wrq_windowsize_test().unwrap();
+    rrq_netascii_test().unwrap();
+    wrq_netascii_test().unwrap();
+    timeout_test().unwrap();
+    timeout_option_test(&server_addr).unwrap();
+    invalid_timeout_option_test(&server_addr).unwrap();
+    adaptive_timeout_test(&server_addr).unwrap();
+    max_retries_test().unwrap();
     wrq_file_exists_test(&server_addr).unwrap();
     rrq_file_not_found_test(&server_addr).unwrap();
+    unknown_mode_test(&server_addr).unwrap();
+    mail_mode_test(&server_addr).unwrap();
+    filename_policy_test(&server_addr).unwrap();
+    root_dir_test().unwrap();
+    read_only_test().unwrap();
+    write_only_test().unwrap();
+    write_policy_overwrite_test().unwrap();
+    temp_file_refused_test().unwrap();
+    write_policy_reject_race_test().unwrap();
+    write_policy_replace_test().unwrap();
+    write_policy_versioned_test().unwrap();
+    wrq_aborted_test().unwrap();
+    memory_storage_versioned_test().unwrap();
+    read_handler_test().unwrap();
+    write_handler_test().unwrap();
+    archive_storage_test().unwrap();
+    custom_storage_test().unwrap();
+    rrq_read_error_test().unwrap();
+    wrq_write_error_test().unwrap();
+    directory_request_test(&server_addr).unwrap();
+    wrq_duplicate_data_test().unwrap();
+    rrq_duplicate_ack_test(&server_addr).unwrap();
+    rrq_unknown_tid_test(&server_addr).unwrap();
+    wrq_unknown_tid_test().unwrap();
+    ipv6_test().unwrap();
+    dual_stack_test().unwrap();
+    sequential_port_range_test().unwrap();
+    random_port_range_test().unwrap();
+    port_range_failed_start_test().unwrap();
+    single_port_test().unwrap();
+    single_port_repeated_rrq_test().unwrap();
+    single_port_repeated_last_data_test().unwrap();
+    wrq_repeated_last_data_test().unwrap();
+    client_error_test(&server_addr).unwrap();
+    single_port_failed_start_test().unwrap();
+    rrq_rollover_test().unwrap();
+    wrq_rollover_test().unwrap();
+    root_dir_symlink_test().unwrap();
 }